@@ -0,0 +1,729 @@
+use colored::*;
+use hyper::header::HeaderValue;
+use hyper::{Body, Request, StatusCode};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::config::{Balance, HashKey, HealthCheck, OutlierDetection, ProxyRoute};
+use crate::dns;
+use crate::pattern;
+use crate::sticky::Affinity;
+
+/// Points each upstream gets on the consistent hashing ring, per unit of weight.
+const RING_POINTS: u64 = 100;
+
+/// Most points the heaviest upstream gets on the ring. Larger weights, like
+/// those of SRV records, are scaled down to fit, keeping their ratios.
+const MAX_RING_POINTS: u64 = 10_000;
+
+/// One upstream of a route, with the state the balancer keeps about it.
+pub struct Upstream {
+    pub url: String,
+    pub weight: u32,
+    outstanding: AtomicUsize,
+    /// Cleared by the health checker while probes fail.
+    healthy: AtomicBool,
+    /// Set once service discovery dropped the upstream.
+    removed: AtomicBool,
+    outlier_detection: Option<OutlierDetection>,
+    circuit: Mutex<Circuit>,
+}
+
+/// Passive failure tracking for one upstream.
+#[derive(Default)]
+struct Circuit {
+    /// Failed requests in a row.
+    failures: u32,
+    /// Set while the upstream is ejected. Once it has passed, the upstream
+    /// is half-open and gets a single trial request.
+    ejected_until: Option<Instant>,
+    /// Whether the trial request is in flight.
+    trial: bool,
+}
+
+impl Upstream {
+    fn new(url: String, weight: u32, outlier_detection: Option<OutlierDetection>) -> Self {
+        Upstream {
+            url,
+            weight,
+            outstanding: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+            removed: AtomicBool::new(false),
+            outlier_detection,
+            circuit: Mutex::new(Circuit::default()),
+        }
+    }
+
+    /// Requests currently in flight to this upstream.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    /// Whether service discovery dropped the upstream from its route.
+    pub fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::Relaxed)
+    }
+
+    /// What sticky session cookies call this upstream. Derived from the
+    /// URL, so cookies stay valid across restarts without revealing it.
+    pub fn id(&self) -> String {
+        format!("{:016x}", hash(self.url.as_bytes()))
+    }
+
+    /// When the upstream's ejection ends, if it is ejected.
+    pub fn ejected_until(&self) -> Option<Instant> {
+        let circuit = self.circuit.lock().unwrap();
+        circuit.ejected_until.filter(|_| !circuit.trial)
+    }
+
+    /// Whether requests may be sent to this upstream.
+    fn is_available(&self) -> bool {
+        if !self.is_healthy() {
+            return false;
+        }
+        let circuit = self.circuit.lock().unwrap();
+        match circuit.ejected_until {
+            None => true,
+            Some(until) => !circuit.trial && Instant::now() >= until,
+        }
+    }
+
+    /// Checks availability and, for a half-open upstream, claims its trial
+    /// request under one lock, so concurrent picks cannot both get it.
+    /// Returns whether this request is the trial, or `None` if the upstream
+    /// is not available.
+    fn claim(&self) -> Option<bool> {
+        if !self.is_healthy() {
+            return None;
+        }
+        let mut circuit = self.circuit.lock().unwrap();
+        match circuit.ejected_until {
+            None => Some(false),
+            Some(until) if !circuit.trial && Instant::now() >= until => {
+                circuit.trial = true;
+                Some(true)
+            }
+            Some(_) => None,
+        }
+    }
+
+    fn record(&self, success: bool, trial: bool) {
+        let Some(outlier) = &self.outlier_detection else {
+            return;
+        };
+        let mut circuit = self.circuit.lock().unwrap();
+        if trial {
+            circuit.trial = false;
+        }
+
+        if success {
+            circuit.failures = 0;
+            if trial {
+                circuit.ejected_until = None;
+                println!(
+                    "{}",
+                    format!("✅ Upstream {} recovered, back in rotation", self.url).green()
+                );
+            }
+            return;
+        }
+
+        circuit.failures += 1;
+        let eject = match trial {
+            true => true,
+            false => {
+                circuit.ejected_until.is_none()
+                    && circuit.failures >= outlier.consecutive_failures.max(1)
+            }
+        };
+        if eject {
+            circuit.ejected_until =
+                Some(Instant::now() + Duration::from_millis(outlier.ejection_ms));
+            eprintln!(
+                "{}",
+                format!(
+                    "❌ Upstream {} ejected for {}ms after {} failures in a row",
+                    self.url, outlier.ejection_ms, circuit.failures
+                )
+                .red()
+            );
+        }
+    }
+}
+
+/// An upstream picked for one request. Counts as outstanding until dropped,
+/// or until the `Outstanding` handed over by `hold` is. The outcome of the
+/// request is reported back with `succeeded` or `failed` for outlier
+/// detection.
+pub struct Pick {
+    pub upstream: Arc<Upstream>,
+    /// The sticky session cookie to set on the response, when the client
+    /// was not already pinned to this upstream.
+    pub set_cookie: Option<HeaderValue>,
+    /// Whether this is the trial request of a half-open upstream.
+    trial: bool,
+    outstanding: Option<Outstanding>,
+}
+
+/// A request counted as outstanding on its upstream until dropped.
+pub struct Outstanding(Arc<Upstream>);
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        self.0.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Pick {
+    /// Hands over the request's outstanding count, so that it can last
+    /// until the response body is done rather than until the outcome is
+    /// reported.
+    pub fn hold(&mut self) -> Outstanding {
+        self.outstanding
+            .take()
+            .expect("a pick's outstanding count is only held once")
+    }
+
+    pub fn succeeded(mut self) {
+        self.upstream.record(true, self.trial);
+        self.trial = false;
+    }
+
+    pub fn failed(mut self) {
+        self.upstream.record(false, self.trial);
+        self.trial = false;
+    }
+}
+
+impl Drop for Pick {
+    fn drop(&mut self) {
+        // A trial that never reached the upstream says nothing about it;
+        // let the next request try instead.
+        if self.trial {
+            self.upstream.circuit.lock().unwrap().trial = false;
+        }
+    }
+}
+
+/// Spreads a route's requests over its upstreams.
+pub struct Balancer {
+    strategy: Balance,
+    members: RwLock<Arc<Members>>,
+    health_check: Option<HealthCheck>,
+    outlier_detection: Option<OutlierDetection>,
+    affinity: Option<Affinity>,
+    /// The SRV record the upstreams are discovered from, if any.
+    srv: Option<String>,
+    next: AtomicUsize,
+}
+
+/// The current set of upstreams. Service discovery swaps in a new set while
+/// requests keep picking from the one they started with.
+struct Members {
+    upstreams: Vec<Arc<Upstream>>,
+    /// Current weights for smooth weighted round-robin.
+    current_weights: Mutex<Vec<i64>>,
+    /// `(hash, upstream index)` points, sorted by hash.
+    ring: Vec<(u64, usize)>,
+}
+
+impl Members {
+    fn new(upstreams: Vec<Arc<Upstream>>, strategy: &Balance) -> Self {
+        let mut ring = Vec::new();
+        if let Balance::ConsistentHash(_) = strategy {
+            let heaviest = upstreams.iter().map(|u| u.weight).max().unwrap_or(1);
+            let heaviest = u64::from(heaviest) * RING_POINTS;
+            let points = |weight: u32| {
+                let points = u64::from(weight) * RING_POINTS;
+                match heaviest > MAX_RING_POINTS {
+                    true => (points * MAX_RING_POINTS / heaviest).max(1),
+                    false => points,
+                }
+            };
+            for (index, upstream) in upstreams.iter().enumerate() {
+                for point in 0..points(upstream.weight) {
+                    let key = format!("{}#{}", upstream.url, point);
+                    ring.push((hash(key.as_bytes()), index));
+                }
+            }
+            ring.sort_unstable();
+        }
+        Members {
+            current_weights: Mutex::new(vec![0; upstreams.len()]),
+            upstreams,
+            ring,
+        }
+    }
+}
+
+impl Balancer {
+    pub fn new(proxy: &ProxyRoute) -> Result<Self, String> {
+        let configured = [
+            !proxy.target.is_empty(),
+            !proxy.targets.is_empty(),
+            !proxy.srv.is_empty(),
+        ];
+        let upstreams = match configured {
+            [true, false, false] => vec![(proxy.target.clone(), 1)],
+            [false, true, false] => proxy
+                .targets
+                .iter()
+                .map(|target| (target.url().to_string(), target.weight()))
+                .collect(),
+            // Filled in by service discovery.
+            [false, false, true] => {
+                dns::parse_srv(&proxy.srv)?;
+                Vec::new()
+            }
+            [false, false, false] => return Err("no target configured".to_string()),
+            _ => return Err("set only one of target, targets and srv".to_string()),
+        };
+        if let Some((url, _)) = upstreams.iter().find(|(_, weight)| *weight == 0) {
+            return Err(format!("weight of {} must be at least 1", url));
+        }
+        if let Some(check) = &proxy.health_check {
+            if let Some(status) = check.expected_status {
+                StatusCode::from_u16(status)
+                    .map_err(|_| format!("invalid health check status {}", status))?;
+            }
+            if let Some((url, _)) = upstreams
+                .iter()
+                .find(|(url, _)| pattern::is_path_template(url) || url.contains('$'))
+            {
+                return Err(format!("cannot health check templated target {}", url));
+            }
+        }
+
+        let upstreams = upstreams
+            .into_iter()
+            .map(|(url, weight)| {
+                Arc::new(Upstream::new(url, weight, proxy.outlier_detection.clone()))
+            })
+            .collect();
+
+        Ok(Balancer {
+            members: RwLock::new(Arc::new(Members::new(upstreams, &proxy.balance))),
+            strategy: proxy.balance.clone(),
+            health_check: proxy.health_check.clone(),
+            outlier_detection: proxy.outlier_detection.clone(),
+            affinity: proxy.sticky.as_ref().map(Affinity::new).transpose()?,
+            srv: Some(proxy.srv.clone()).filter(|srv| !srv.is_empty()),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Replaces the upstreams with the ones service discovery found.
+    /// Upstreams that stay keep their state. Returns the new ones.
+    pub fn set_upstreams(&self, discovered: Vec<(String, u32)>) -> Vec<Arc<Upstream>> {
+        let mut members = self.members.write().unwrap();
+        let mut added = Vec::new();
+        let upstreams: Vec<Arc<Upstream>> = discovered
+            .into_iter()
+            .map(|(url, weight)| {
+                let existing = members
+                    .upstreams
+                    .iter()
+                    .find(|upstream| upstream.url == url && upstream.weight == weight);
+                match existing {
+                    Some(upstream) => Arc::clone(upstream),
+                    None => {
+                        let upstream =
+                            Arc::new(Upstream::new(url, weight, self.outlier_detection.clone()));
+                        added.push(Arc::clone(&upstream));
+                        upstream
+                    }
+                }
+            })
+            .collect();
+
+        let removed: Vec<&Arc<Upstream>> = members
+            .upstreams
+            .iter()
+            .filter(|old| !upstreams.iter().any(|new| Arc::ptr_eq(old, new)))
+            .collect();
+        if added.is_empty() && removed.is_empty() {
+            return added;
+        }
+        for upstream in &removed {
+            upstream.removed.store(true, Ordering::Relaxed);
+        }
+        let urls: Vec<&str> = upstreams.iter().map(|u| u.url.as_str()).collect();
+        println!(
+            "{}",
+            format!(
+                "🧭 SRV {}: upstreams now {}",
+                self.srv.as_deref().unwrap_or_default(),
+                urls.join(", ")
+            )
+            .cyan()
+        );
+
+        *members = Arc::new(Members::new(upstreams, &self.strategy));
+        added
+    }
+
+    /// The current upstreams.
+    pub fn upstreams(&self) -> Vec<Arc<Upstream>> {
+        self.members().upstreams.clone()
+    }
+
+    fn members(&self) -> Arc<Members> {
+        Arc::clone(&self.members.read().unwrap())
+    }
+
+    pub fn strategy(&self) -> &Balance {
+        &self.strategy
+    }
+
+    pub fn health_check(&self) -> Option<&HealthCheck> {
+        self.health_check.as_ref()
+    }
+
+    pub fn outlier_detection(&self) -> Option<&OutlierDetection> {
+        self.outlier_detection.as_ref()
+    }
+
+    pub fn srv(&self) -> Option<&str> {
+        self.srv.as_deref()
+    }
+
+    /// How long until an upstream may be available again, for the
+    /// `Retry-After` of a `503`: the end of the earliest ejection, or the
+    /// health check interval if upstreams are only out for failing probes.
+    pub fn retry_after(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let upstreams = self.upstreams();
+        let ejection = upstreams
+            .iter()
+            .filter_map(|upstream| upstream.ejected_until())
+            .map(|until| until.saturating_duration_since(now))
+            .min();
+        let health_check = self
+            .health_check
+            .as_ref()
+            .filter(|_| upstreams.iter().any(|u| !u.is_healthy()))
+            .map(|check| Duration::from_millis(check.interval_ms));
+        ejection.or(health_check)
+    }
+
+    /// Picks the upstream for `req` among the available ones, or `None` if
+    /// every upstream is out of rotation. A sticky session's upstream comes
+    /// first while it is available.
+    pub fn pick(&self, client_ip: IpAddr, req: &Request<Body>) -> Option<Pick> {
+        let members = self.members();
+        let pinned = self
+            .affinity
+            .as_ref()
+            .and_then(|affinity| affinity.pinned(req));
+        let mut sticky = pinned.and_then(|id| {
+            members
+                .upstreams
+                .iter()
+                .position(|upstream| upstream.id() == id && upstream.is_available())
+        });
+        // An upstream can drop out between being chosen and being claimed,
+        // e.g. when a concurrent request took its half-open trial. Each lost
+        // claim leaves one fewer available upstream, so this ends.
+        let (index, trial) = loop {
+            let index = match sticky {
+                Some(index) => Some(index),
+                None if members.upstreams.is_empty() => None,
+                None if members.upstreams.len() == 1 => {
+                    Some(0).filter(|_| members.upstreams[0].is_available())
+                }
+                None => match &self.strategy {
+                    Balance::RoundRobin => self.round_robin(&members),
+                    Balance::WeightedRoundRobin => self.weighted_round_robin(&members),
+                    Balance::Random => self.random(&members),
+                    Balance::LeastOutstanding => self.least_outstanding(&members),
+                    Balance::ConsistentHash(key) => {
+                        self.consistent_hash(&members, key, client_ip, req)
+                    }
+                },
+            }?;
+            if let Some(trial) = members.upstreams[index].claim() {
+                break (index, trial);
+            }
+            sticky = None;
+        };
+
+        let upstream = Arc::clone(&members.upstreams[index]);
+        upstream.outstanding.fetch_add(1, Ordering::Relaxed);
+        let set_cookie = match (&self.affinity, sticky) {
+            (Some(affinity), None) => Some(affinity.set_cookie(&upstream.id())),
+            _ => None,
+        };
+        Some(Pick {
+            outstanding: Some(Outstanding(Arc::clone(&upstream))),
+            upstream,
+            set_cookie,
+            trial,
+        })
+    }
+
+    fn available(members: &Members) -> Vec<usize> {
+        (0..members.upstreams.len())
+            .filter(|&index| members.upstreams[index].is_available())
+            .collect()
+    }
+
+    /// Indices of all upstreams, starting at a rotating offset.
+    fn rotation(&self, members: &Members) -> impl Iterator<Item = usize> {
+        let len = members.upstreams.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+        (0..len).map(move |offset| (start + offset) % len)
+    }
+
+    /// Takes the available upstreams in turn, so the load of an unavailable
+    /// one is spread evenly instead of landing on its neighbour.
+    fn round_robin(&self, members: &Members) -> Option<usize> {
+        let available = Self::available(members);
+        if available.is_empty() {
+            return None;
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        Some(available[next % available.len()])
+    }
+
+    /// nginx's smooth weighted round-robin: every pick raises each upstream's
+    /// current weight by its weight, takes the highest, and lowers that one
+    /// by the total. Heavier upstreams get more requests without bursts.
+    /// Unavailable upstreams sit out without building up weight.
+    fn weighted_round_robin(&self, members: &Members) -> Option<usize> {
+        let mut current = members.current_weights.lock().unwrap();
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+
+        for (index, upstream) in members.upstreams.iter().enumerate() {
+            if !upstream.is_available() {
+                continue;
+            }
+            current[index] += i64::from(upstream.weight);
+            total += i64::from(upstream.weight);
+            if best.is_none_or(|best| current[index] > current[best]) {
+                best = Some(index);
+            }
+        }
+        current[best?] -= total;
+        best
+    }
+
+    fn random(&self, members: &Members) -> Option<usize> {
+        let available = Self::available(members);
+        if available.is_empty() {
+            return None;
+        }
+        Some(available[(random_u64() % available.len() as u64) as usize])
+    }
+
+    /// The upstream with the fewest requests in flight. Starts scanning at a
+    /// rotating offset so ties don't always go to the first upstream.
+    fn least_outstanding(&self, members: &Members) -> Option<usize> {
+        self.rotation(members)
+            .filter(|&index| members.upstreams[index].is_available())
+            .min_by_key(|&index| members.upstreams[index].outstanding())
+    }
+
+    /// Maps the hash key onto the ring, so a given client or header value
+    /// keeps reaching the same upstream as long as the set of upstreams
+    /// doesn't change. Requests without the header hash on the client IP.
+    /// If that upstream is unavailable, the ring is walked on to the next
+    /// available one, so only its keys move.
+    fn consistent_hash(
+        &self,
+        members: &Members,
+        key: &HashKey,
+        client_ip: IpAddr,
+        req: &Request<Body>,
+    ) -> Option<usize> {
+        let value = match key {
+            HashKey::ClientIp => None,
+            HashKey::Header(name) => req.headers().get(name.as_str()).map(|v| v.as_bytes()),
+        };
+        let hash = match value {
+            Some(value) => hash(value),
+            None => hash(client_ip.to_string().as_bytes()),
+        };
+
+        let start = members.ring.partition_point(|(point, _)| *point < hash);
+        let (after, before) = members.ring.split_at(start);
+        before
+            .iter()
+            .chain(after)
+            .map(|(_, index)| *index)
+            .find(|&index| members.upstreams[index].is_available())
+    }
+}
+
+/// 64-bit FNV-1a, finished with MurmurHash3's `fmix64` so that short,
+/// similar keys still land far apart on the ring. Stable across runs and
+/// builds, unlike `DefaultHasher`.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn consistent_hash(targets: serde_json::Value) -> Balancer {
+        let proxy: ProxyRoute = serde_json::from_value(serde_json::json!({
+            "targets": targets,
+            "balance": { "consistent_hash": "client_ip" },
+        }))
+        .unwrap();
+        Balancer::new(&proxy).unwrap()
+    }
+
+    #[test]
+    fn ring_keeps_small_weights_exact() {
+        let balancer = consistent_hash(serde_json::json!([
+            { "url": "http://a.test", "weight": 3 },
+            "http://b.test",
+        ]));
+        let members = balancer.members.read().unwrap();
+        let on_a = members.ring.iter().filter(|(_, index)| *index == 0).count();
+        assert_eq!((on_a, members.ring.len()), (300, 400));
+    }
+
+    #[test]
+    fn ring_scales_heavy_weights_down() {
+        let balancer = consistent_hash(serde_json::json!(["http://a.test"]));
+        // Weights as SRV records may carry them, and beyond.
+        balancer.set_upstreams(vec![("http://huge.test".to_string(), u32::MAX)]);
+        balancer.set_upstreams(vec![
+            ("http://a.test".to_string(), 65535),
+            ("http://b.test".to_string(), 32768),
+            ("http://c.test".to_string(), 1),
+        ]);
+        let members = balancer.members.read().unwrap();
+        let count = |upstream| members.ring.iter().filter(|(_, i)| *i == upstream).count();
+        assert_eq!(count(0), MAX_RING_POINTS as usize);
+        assert_eq!(count(1), 5000);
+        // Still on the ring, however light.
+        assert_eq!(count(2), 1);
+    }
+
+    #[test]
+    fn held_pick_stays_outstanding() {
+        let proxy: ProxyRoute =
+            serde_json::from_value(serde_json::json!({ "target": "http://a.test" })).unwrap();
+        let balancer = Balancer::new(&proxy).unwrap();
+        let req = Request::new(Body::empty());
+
+        let mut pick = balancer.pick(IpAddr::from([127, 0, 0, 1]), &req).unwrap();
+        let upstream = Arc::clone(&pick.upstream);
+        let held = pick.hold();
+        pick.succeeded();
+        assert_eq!(upstream.outstanding(), 1);
+        drop(held);
+        assert_eq!(upstream.outstanding(), 0);
+
+        drop(balancer.pick(IpAddr::from([127, 0, 0, 1]), &req));
+        assert_eq!(upstream.outstanding(), 0);
+    }
+
+    #[test]
+    fn half_open_upstream_admits_one_trial() {
+        let proxy: ProxyRoute = serde_json::from_value(serde_json::json!({
+            "targets": ["http://a.test", "http://b.test"],
+            "outlier_detection": { "consecutive_failures": 1, "ejection_ms": 20 },
+        }))
+        .unwrap();
+        let balancer = Balancer::new(&proxy).unwrap();
+        let a = Arc::clone(&balancer.upstreams()[0]);
+        let req = Request::new(Body::empty());
+        let pick = || balancer.pick(IpAddr::from([127, 0, 0, 1]), &req).unwrap();
+
+        let first = std::iter::repeat_with(pick)
+            .find(|pick| Arc::ptr_eq(&pick.upstream, &a))
+            .unwrap();
+        first.failed();
+        assert!(a.ejected_until().is_some());
+        std::thread::sleep(Duration::from_millis(30));
+
+        let picks: Vec<Pick> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..16).map(|_| scope.spawn(pick)).collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        let trials: Vec<&Pick> = picks.iter().filter(|p| p.trial).collect();
+        assert_eq!(trials.len(), 1);
+        assert!(Arc::ptr_eq(&trials[0].upstream, &a));
+        let on_a = picks.iter().filter(|p| Arc::ptr_eq(&p.upstream, &a));
+        assert_eq!(on_a.count(), 1);
+    }
+
+    #[test]
+    fn sticky_cookie_follows_its_upstream_until_ejected() {
+        let proxy: ProxyRoute = serde_json::from_value(serde_json::json!({
+            "targets": ["http://a.test", "http://b.test", "http://c.test"],
+            "sticky": { "cookie": "backend" },
+            "outlier_detection": { "consecutive_failures": 1, "ejection_ms": 60000 },
+        }))
+        .unwrap();
+        let balancer = Balancer::new(&proxy).unwrap();
+        let client_ip = IpAddr::from([127, 0, 0, 1]);
+        let with_cookie = |cookie: &HeaderValue| {
+            let pair = cookie.to_str().unwrap().split(';').next().unwrap();
+            let req = Request::get("/")
+                .header(hyper::header::COOKIE, pair)
+                .body(Body::empty())
+                .unwrap();
+            balancer.pick(client_ip, &req).unwrap()
+        };
+
+        let first = balancer
+            .pick(client_ip, &Request::new(Body::empty()))
+            .unwrap();
+        let cookie = first.set_cookie.clone().unwrap();
+        let pinned = Arc::clone(&first.upstream);
+        assert!(cookie
+            .to_str()
+            .unwrap()
+            .starts_with(&format!("backend={};", pinned.id())));
+        first.succeeded();
+
+        for _ in 0..10 {
+            let pick = with_cookie(&cookie);
+            assert!(Arc::ptr_eq(&pick.upstream, &pinned));
+            assert!(pick.set_cookie.is_none());
+        }
+
+        with_cookie(&cookie).failed();
+        let moved = with_cookie(&cookie);
+        assert!(!Arc::ptr_eq(&moved.upstream, &pinned));
+        let fresh = moved.set_cookie.clone().unwrap();
+        assert!(fresh
+            .to_str()
+            .unwrap()
+            .starts_with(&format!("backend={};", moved.upstream.id())));
+
+        // The new cookie sticks to the new upstream.
+        let again = with_cookie(&fresh);
+        assert!(Arc::ptr_eq(&again.upstream, &moved.upstream));
+        assert!(again.set_cookie.is_none());
+    }
+}
@@ -0,0 +1,952 @@
+use colored::*;
+use serde::de::Error as _;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::predicate::Predicate;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProxyConfig {
+    #[serde(deserialize_with = "deserialize_routes")]
+    pub routes: HashMap<String, Route>,
+    /// What to do with requests no route matches.
+    #[serde(default, skip_serializing_if = "Fallback::is_default")]
+    pub fallback: Fallback,
+    /// Caps retries across all routes.
+    #[serde(default, skip_serializing_if = "RetryBudget::is_default")]
+    pub retry_budget: RetryBudget,
+    /// Timeouts for proxy routes that don't set their own.
+    #[serde(default, skip_serializing_if = "Timeouts::is_empty")]
+    pub timeouts: Timeouts,
+    /// How connections to upstreams are kept and reused.
+    #[serde(default, skip_serializing_if = "ConnectionPool::is_default")]
+    pub pool: ConnectionPool,
+    /// How upstream host names are resolved.
+    #[serde(default, skip_serializing_if = "DnsConfig::is_default")]
+    pub dns: DnsConfig,
+    /// A forward proxy for routes without an `upstream_proxy` of their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_proxy: Option<UpstreamProxy>,
+    /// Template files for the error pages prin generates, by status code.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub error_pages: HashMap<u16, String>,
+}
+
+/// A route in `ProxyConfig.routes`. The map key names the route and, unless
+/// `path` is set, doubles as its path prefix.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Route {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Regex matched against the whole request path instead of a prefix.
+    /// Its groups, like the `{name}` segments of a parameterized `path`, can
+    /// be used in `target` and `request_headers`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+    /// Exact host name or `*.example.test` wildcard; routes without a host
+    /// match requests for any host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Further conditions on the method, headers, cookies or query; all of
+    /// them have to hold for the route to match.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub predicates: Vec<Predicate>,
+    /// Breaks ties between routes that are equally specific; higher wins.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    #[serde(
+        rename = "match",
+        default,
+        skip_serializing_if = "MatchMode::is_default"
+    )]
+    pub match_mode: MatchMode,
+    /// What the route does with a matched request, selected by `type`.
+    #[serde(flatten, deserialize_with = "deserialize_action")]
+    pub action: RouteAction,
+}
+
+/// How a route prefix is compared against the request path.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    /// `/api` matches `/api` and `/api/...`, but not `/apiary`.
+    #[default]
+    Segment,
+    /// Plain string prefix: `/api` also matches `/apiary` and `/api-docs`.
+    RawPrefix,
+}
+
+impl MatchMode {
+    fn is_default(&self) -> bool {
+        *self == MatchMode::default()
+    }
+}
+
+/// The route types. Routes without a `type` are `proxy` routes.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RouteAction {
+    Proxy(Box<ProxyRoute>),
+    Redirect(RedirectRoute),
+    Static(StaticRoute),
+    Mock(MockRoute),
+    /// Answers with a JSON description of the request, headers included.
+    DebugEcho,
+    /// Answers with prin's metrics in the Prometheus text format.
+    Metrics,
+}
+
+impl RouteAction {
+    /// One-line description for route listings.
+    pub fn summary(&self) -> String {
+        match self {
+            RouteAction::Proxy(proxy) => proxy.summary(),
+            RouteAction::Redirect(redirect) => redirect.summary(),
+            RouteAction::Static(files) => format!("📁 {}", files.root),
+            RouteAction::Mock(mock) => mock.summary(),
+            RouteAction::DebugEcho => "🐞 debug echo".to_string(),
+            RouteAction::Metrics => "📊 metrics".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProxyRoute {
+    /// The upstream, for routes with a single one.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub target: String,
+    /// A pool of upstreams to balance between, instead of `target`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<UpstreamTarget>,
+    /// Discovers the upstreams from an SRV record instead, e.g.
+    /// `http://_api._tcp.example.internal`, and keeps them up to date.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub srv: String,
+    #[serde(default, skip_serializing_if = "Balance::is_default")]
+    pub balance: Balance,
+    /// Whether the matched prefix is removed before forwarding (default on).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strip_prefix: Option<bool>,
+    /// Prepended to the forwarded path, e.g. `/internal`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub add_prefix: Option<String>,
+    /// Forwards every request to this literal path instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replace_path: Option<String>,
+    /// Headers set on the forwarded request.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub request_headers: HashMap<String, String>,
+    /// Probes the upstreams in the background and stops sending requests to
+    /// the ones that fail.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheck>,
+    /// Takes upstreams out of rotation for a while when real requests to
+    /// them keep failing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outlier_detection: Option<OutlierDetection>,
+    /// Keeps sending a client to the upstream that answered its first
+    /// request, by way of a cookie.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sticky: Option<StickySession>,
+    /// Retries failed requests, on the same or another upstream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+    /// Overrides the global `timeouts` field by field.
+    #[serde(default, skip_serializing_if = "Timeouts::is_empty")]
+    pub timeouts: Timeouts,
+    /// How `https://` upstreams are verified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<UpstreamTls>,
+    /// Reaches the upstreams through this forward proxy instead of the
+    /// global one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_proxy: Option<UpstreamProxy>,
+    /// The HTTP version spoken to the upstreams.
+    #[serde(default, skip_serializing_if = "UpstreamProtocol::is_default")]
+    pub protocol: UpstreamProtocol,
+}
+
+impl ProxyRoute {
+    pub fn new(target: String) -> Self {
+        ProxyRoute {
+            target,
+            targets: Vec::new(),
+            srv: String::new(),
+            balance: Balance::default(),
+            strip_prefix: None,
+            add_prefix: None,
+            replace_path: None,
+            request_headers: HashMap::new(),
+            health_check: None,
+            outlier_detection: None,
+            sticky: None,
+            retry: None,
+            timeouts: Timeouts::default(),
+            tls: None,
+            upstream_proxy: None,
+            protocol: UpstreamProtocol::default(),
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        if !self.srv.is_empty() {
+            return format!("SRV {}", self.srv);
+        }
+        if self.targets.is_empty() {
+            return self.target.clone();
+        }
+        let targets: Vec<&str> = self.targets.iter().map(UpstreamTarget::url).collect();
+        targets.join(", ")
+    }
+}
+
+/// An entry of `targets`: either just a URL or a URL with a weight.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum UpstreamTarget {
+    Url(String),
+    Weighted { url: String, weight: u32 },
+}
+
+impl UpstreamTarget {
+    pub fn url(&self) -> &str {
+        match self {
+            UpstreamTarget::Url(url) | UpstreamTarget::Weighted { url, .. } => url,
+        }
+    }
+
+    pub fn weight(&self) -> u32 {
+        match self {
+            UpstreamTarget::Url(_) => 1,
+            UpstreamTarget::Weighted { weight, .. } => *weight,
+        }
+    }
+}
+
+/// How a route with several `targets` picks one for each request.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    WeightedRoundRobin,
+    Random,
+    /// The upstream with the fewest requests in flight.
+    LeastOutstanding,
+    /// Requests with the same key always go to the same upstream.
+    ConsistentHash(HashKey),
+}
+
+impl Balance {
+    fn is_default(&self) -> bool {
+        *self == Balance::default()
+    }
+}
+
+impl fmt::Display for Balance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Balance::RoundRobin => write!(f, "round_robin"),
+            Balance::WeightedRoundRobin => write!(f, "weighted_round_robin"),
+            Balance::Random => write!(f, "random"),
+            Balance::LeastOutstanding => write!(f, "least_outstanding"),
+            Balance::ConsistentHash(HashKey::ClientIp) => write!(f, "consistent_hash on client IP"),
+            Balance::ConsistentHash(HashKey::Header(name)) => {
+                write!(f, "consistent_hash on header {}", name)
+            }
+        }
+    }
+}
+
+/// The HTTP version a proxy route speaks to its upstreams. Over HTTP/2,
+/// concurrent requests share one connection per upstream.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamProtocol {
+    #[default]
+    Http1,
+    /// HTTP/2 when an `https://` upstream offers it during the TLS
+    /// handshake (ALPN), HTTP/1.1 otherwise.
+    Auto,
+    /// HTTP/2 only: negotiated via ALPN over TLS, and with prior knowledge
+    /// (h2c) for plain `http://` and `unix:` targets.
+    Http2,
+}
+
+impl UpstreamProtocol {
+    fn is_default(&self) -> bool {
+        *self == UpstreamProtocol::default()
+    }
+}
+
+impl fmt::Display for UpstreamProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamProtocol::Http1 => write!(f, "HTTP/1.1"),
+            UpstreamProtocol::Auto => write!(f, "HTTP/2 if offered over TLS, else HTTP/1.1"),
+            UpstreamProtocol::Http2 => write!(f, "HTTP/2 (h2c without TLS)"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    ClientIp,
+    Header(String),
+}
+
+/// Active health checking for a proxy route's upstreams.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HealthCheck {
+    /// Path to send a `GET` to, relative to the target. Without one, a probe
+    /// only opens a TCP connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Status the probe expects; any 2xx when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_status: Option<u16>,
+    #[serde(default = "default_health_interval")]
+    pub interval_ms: u64,
+    #[serde(default = "default_health_timeout")]
+    pub timeout_ms: u64,
+    /// Failed probes in a row before an upstream is taken out of rotation.
+    #[serde(default = "default_health_threshold")]
+    pub unhealthy_threshold: u32,
+    /// Passed probes in a row before it is put back.
+    #[serde(default = "default_health_threshold")]
+    pub healthy_threshold: u32,
+}
+
+impl HealthCheck {
+    pub fn summary(&self) -> String {
+        let probe = match &self.path {
+            Some(path) => format!("GET {}", path),
+            None => "TCP connect".to_string(),
+        };
+        format!("🩺 {} every {}ms", probe, self.interval_ms)
+    }
+}
+
+fn default_health_interval() -> u64 {
+    10_000
+}
+
+fn default_health_timeout() -> u64 {
+    2_000
+}
+
+fn default_health_threshold() -> u32 {
+    2
+}
+
+/// Passive failure tracking for a proxy route's upstreams. Connection
+/// errors, timeouts and 5xx responses count as failures.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OutlierDetection {
+    /// Failures in a row before an upstream is ejected.
+    #[serde(default = "default_consecutive_failures")]
+    pub consecutive_failures: u32,
+    /// How long an ejected upstream gets no requests. Afterwards a single
+    /// trial request decides whether it is put back or ejected again.
+    #[serde(default = "default_ejection")]
+    pub ejection_ms: u64,
+}
+
+impl OutlierDetection {
+    pub fn summary(&self) -> String {
+        format!(
+            "🔌 eject for {}ms after {} failures in a row",
+            self.ejection_ms, self.consecutive_failures
+        )
+    }
+}
+
+fn default_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_ejection() -> u64 {
+    30_000
+}
+
+/// Session affinity for a proxy route. The response to a client without
+/// the cookie sets it to an id of the upstream that answered; requests
+/// carrying it go to that upstream for as long as it is available, and are
+/// balanced and pinned anew otherwise.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StickySession {
+    #[serde(default = "default_sticky_cookie")]
+    pub cookie: String,
+    /// How long the cookie lasts. Without it, it lasts until the browser
+    /// is closed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_ms: Option<u64>,
+    #[serde(default = "default_sticky_path")]
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub secure: bool,
+    #[serde(default = "default_sticky_http_only")]
+    pub http_only: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub same_site: Option<SameSite>,
+}
+
+impl StickySession {
+    pub fn summary(&self) -> String {
+        match self.ttl_ms {
+            Some(ttl) => format!("🍪 sticky via cookie {} for {}ms", self.cookie, ttl),
+            None => format!("🍪 sticky via cookie {} for the session", self.cookie),
+        }
+    }
+}
+
+fn default_sticky_cookie() -> String {
+    "prin_upstream".to_string()
+}
+
+fn default_sticky_path() -> String {
+    "/".to_string()
+}
+
+fn default_sticky_http_only() -> bool {
+    true
+}
+
+/// The `SameSite` attribute of a cookie.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SameSite::Strict => write!(f, "Strict"),
+            SameSite::Lax => write!(f, "Lax"),
+            SameSite::None => write!(f, "None"),
+        }
+    }
+}
+
+/// When and how a proxy route retries a failed request. Only requests with
+/// idempotent methods are retried unless `non_idempotent` is set, and only
+/// if their body is small enough to keep around for another attempt.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RetryPolicy {
+    /// Attempts in total, the first one included.
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: u32,
+    #[serde(default = "RetryOn::defaults")]
+    pub retry_on: Vec<RetryOn>,
+    /// Delay before the first retry, doubled for every further one.
+    #[serde(default = "default_retry_backoff")]
+    pub backoff_ms: u64,
+    #[serde(default = "default_retry_max_backoff")]
+    pub max_backoff_ms: u64,
+    /// Also retries `POST`, `PATCH` and other non-idempotent requests.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub non_idempotent: bool,
+    /// Requests with larger bodies are sent only once.
+    #[serde(default = "default_retry_max_body")]
+    pub max_body_bytes: u64,
+}
+
+impl RetryPolicy {
+    pub fn summary(&self) -> String {
+        let retry_on: Vec<String> = self.retry_on.iter().map(|r| r.to_string()).collect();
+        format!(
+            "🔁 up to {} attempts on {}",
+            self.attempts,
+            retry_on.join(", ")
+        )
+    }
+}
+
+/// Failures a request can be retried after.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// The upstream could not be connected to.
+    ConnectFailure,
+    /// The connection was reset or closed before a response arrived.
+    Reset,
+    /// The upstream answered with a 5xx status.
+    #[serde(rename = "5xx")]
+    ServerError,
+    /// Connecting or waiting for the response headers took too long.
+    Timeout,
+}
+
+impl RetryOn {
+    fn defaults() -> Vec<RetryOn> {
+        vec![RetryOn::ConnectFailure, RetryOn::Reset]
+    }
+}
+
+impl fmt::Display for RetryOn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetryOn::ConnectFailure => write!(f, "connect_failure"),
+            RetryOn::Reset => write!(f, "reset"),
+            RetryOn::ServerError => write!(f, "5xx"),
+            RetryOn::Timeout => write!(f, "timeout"),
+        }
+    }
+}
+
+fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_backoff() -> u64 {
+    25
+}
+
+fn default_retry_max_backoff() -> u64 {
+    1_000
+}
+
+fn default_retry_max_body() -> u64 {
+    64 * 1024
+}
+
+/// Limits on how long prin waits for an upstream. Unset fields fall back to
+/// the global `timeouts`, then to the built-in defaults.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Timeouts {
+    /// Opening the connection, TLS handshake included.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_ms: Option<u64>,
+    /// From having a connection until the response headers arrive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_byte_ms: Option<u64>,
+    /// The whole exchange, retries and response body included. No limit by
+    /// default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_ms: Option<u64>,
+    /// Longest pause between two chunks of the response body. No limit by
+    /// default, so quiet event streams stay open.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_ms: Option<u64>,
+}
+
+impl Timeouts {
+    const DEFAULT_CONNECT_MS: u64 = 10_000;
+    const DEFAULT_FIRST_BYTE_MS: u64 = 60_000;
+
+    /// These timeouts with unset fields taken from `global`, then from the
+    /// built-in defaults.
+    pub fn resolve(&self, global: &Timeouts) -> Timeouts {
+        Timeouts {
+            connect_ms: self
+                .connect_ms
+                .or(global.connect_ms)
+                .or(Some(Self::DEFAULT_CONNECT_MS)),
+            first_byte_ms: self
+                .first_byte_ms
+                .or(global.first_byte_ms)
+                .or(Some(Self::DEFAULT_FIRST_BYTE_MS)),
+            total_ms: self.total_ms.or(global.total_ms),
+            idle_ms: self.idle_ms.or(global.idle_ms),
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let show = |ms: Option<u64>| ms.map_or("none".to_string(), |ms| format!("{}ms", ms));
+        format!(
+            "⏱️ connect {}, first byte {}, idle {}, total {}",
+            show(self.connect_ms),
+            show(self.first_byte_ms),
+            show(self.idle_ms),
+            show(self.total_ms)
+        )
+    }
+
+    fn is_empty(&self) -> bool {
+        self.connect_ms.is_none()
+            && self.first_byte_ms.is_none()
+            && self.total_ms.is_none()
+            && self.idle_ms.is_none()
+    }
+}
+
+/// TLS settings for a proxy route's `https://` upstreams.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq, Hash)]
+pub struct UpstreamTls {
+    /// PEM file with the certificate authorities to trust instead of the
+    /// system's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<String>,
+    /// Name sent as SNI and checked against the certificate, instead of the
+    /// target's host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+    /// Accepts any certificate. Only meant for self-signed dev backends.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub insecure_skip_verify: bool,
+    /// PEM certificate chain presented to upstreams that ask for one.
+    /// Reloaded when it changes on disk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<String>,
+    /// PEM private key for `client_cert`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<String>,
+}
+
+impl UpstreamTls {
+    pub fn summary(&self) -> String {
+        let mut parts = vec![match &self.ca_file {
+            Some(ca_file) => format!("CA {}", ca_file),
+            None => "system roots".to_string(),
+        }];
+        if let Some(server_name) = &self.server_name {
+            parts.push(format!("SNI {}", server_name));
+        }
+        if let Some(client_cert) = &self.client_cert {
+            parts.push(format!("client cert {}", client_cert));
+        }
+        if self.insecure_skip_verify {
+            parts.push("⚠️ certificates not verified".to_string());
+        }
+        format!("🔒 TLS: {}", parts.join(", "))
+    }
+}
+
+/// Keeps retries from multiplying the load on a service that is already
+/// failing: over roughly the last ten seconds, retries may add at most
+/// `percent` of the requests, plus `min_per_second` so that quiet routes
+/// can still retry.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RetryBudget {
+    #[serde(default = "default_retry_percent")]
+    pub percent: u32,
+    #[serde(default = "default_retry_min_per_second")]
+    pub min_per_second: u32,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        RetryBudget {
+            percent: default_retry_percent(),
+            min_per_second: default_retry_min_per_second(),
+        }
+    }
+}
+
+impl RetryBudget {
+    fn is_default(&self) -> bool {
+        *self == RetryBudget::default()
+    }
+}
+
+fn default_retry_percent() -> u32 {
+    20
+}
+
+fn default_retry_min_per_second() -> u32 {
+    3
+}
+
+/// Limits for the connections each proxy route keeps to its upstreams.
+/// Every upstream host gets a pool of its own.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ConnectionPool {
+    /// Idle connections kept open per upstream host.
+    #[serde(default = "default_pool_max_idle")]
+    pub max_idle_per_host: usize,
+    /// How long an idle connection is kept before it is closed.
+    #[serde(default = "default_pool_idle_timeout")]
+    pub idle_timeout_ms: u64,
+    /// Connections open at once per upstream host. Requests beyond it wait
+    /// for a connection to free up. No limit by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections_per_host: Option<usize>,
+    /// Interval of TCP keepalive probes, or `null` to send none.
+    #[serde(default = "default_pool_keepalive")]
+    pub tcp_keepalive_ms: Option<u64>,
+    /// Sends small writes right away instead of batching them (Nagle).
+    #[serde(default = "default_pool_nodelay")]
+    pub tcp_nodelay: bool,
+}
+
+impl Default for ConnectionPool {
+    fn default() -> Self {
+        ConnectionPool {
+            max_idle_per_host: default_pool_max_idle(),
+            idle_timeout_ms: default_pool_idle_timeout(),
+            max_connections_per_host: None,
+            tcp_keepalive_ms: default_pool_keepalive(),
+            tcp_nodelay: default_pool_nodelay(),
+        }
+    }
+}
+
+impl ConnectionPool {
+    fn is_default(&self) -> bool {
+        *self == ConnectionPool::default()
+    }
+}
+
+fn default_pool_max_idle() -> usize {
+    32
+}
+
+fn default_pool_idle_timeout() -> u64 {
+    90_000
+}
+
+fn default_pool_keepalive() -> Option<u64> {
+    Some(60_000)
+}
+
+fn default_pool_nodelay() -> bool {
+    true
+}
+
+/// How upstream host names are resolved. Answers are used until their TTL
+/// runs out, then looked up again, so address changes are picked up while
+/// prin runs.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct DnsConfig {
+    /// Name servers to ask, as `ip` or `ip:port`. By default host names go
+    /// through the system resolver.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nameservers: Vec<String>,
+    /// How long answers are used, instead of the records' own TTL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_ms: Option<u64>,
+}
+
+impl DnsConfig {
+    fn is_default(&self) -> bool {
+        *self == DnsConfig::default()
+    }
+}
+
+/// A forward proxy that upstream connections go through: `https://`
+/// upstreams through a `CONNECT` tunnel, plain HTTP ones by sending the
+/// proxy absolute-form requests.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct UpstreamProxy {
+    /// The proxy's address, e.g. `http://proxy.corp.example:3128`.
+    pub url: String,
+    /// Sent to the proxy with basic auth, along with `password`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Hosts reached directly, in addition to those in the `NO_PROXY`
+    /// environment variable.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub no_proxy: Vec<String>,
+}
+
+/// Answers with a redirect instead of proxying.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RedirectRoute {
+    /// Template for the `Location` header. `{path}` is the path left after
+    /// the route's prefix, `{query}` the request's query string including
+    /// its `?`, and pattern routes can use their captured groups.
+    pub location: String,
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+impl RedirectRoute {
+    pub const STATUSES: [u16; 4] = [301, 302, 307, 308];
+
+    pub fn summary(&self) -> String {
+        format!("🔀 {} redirect to {}", self.status, self.location)
+    }
+}
+
+/// Serves files from a local directory.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StaticRoute {
+    /// Directory to serve, relative to where `prin start` runs.
+    pub root: String,
+    /// Serves `index.html` for paths that match no file, for single-page
+    /// apps with client-side routing.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub spa: bool,
+}
+
+/// Answers with a fixed response, without any upstream.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MockRoute {
+    #[serde(default = "default_mock_status")]
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    /// Inline response body.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// File to read the response body from, relative to where `prin start`
+    /// runs. Read on every request, so edits show up right away.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_file: Option<String>,
+    /// Substitutes `{path}`, `{query}` and captured groups into the body and
+    /// header values.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub template: bool,
+}
+
+impl MockRoute {
+    pub fn summary(&self) -> String {
+        let body = match (&self.body_file, &self.body) {
+            (Some(file), _) => file.clone(),
+            (None, Some(_)) => "inline body".to_string(),
+            (None, None) => "empty body".to_string(),
+        };
+        format!("🧪 mock {} with {}", self.status, body)
+    }
+}
+
+fn default_mock_status() -> u16 {
+    200
+}
+
+/// Response for requests that match no route.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Fallback {
+    /// A plain `404 Not Found` page.
+    #[default]
+    NotFound,
+    /// Forwards the request, path unchanged, to a default upstream.
+    Proxy(Box<ProxyRoute>),
+    /// Redirects unmatched requests; `{path}` is the full request path.
+    Redirect(RedirectRoute),
+}
+
+impl Fallback {
+    fn is_default(&self) -> bool {
+        matches!(self, Fallback::NotFound)
+    }
+}
+
+fn default_redirect_status() -> u16 {
+    302
+}
+
+impl Route {
+    pub fn new(action: RouteAction) -> Self {
+        Route {
+            path: None,
+            regex: None,
+            host: None,
+            predicates: Vec::new(),
+            priority: None,
+            match_mode: MatchMode::default(),
+            action,
+        }
+    }
+
+    pub fn proxy(target: String) -> Self {
+        Route::new(RouteAction::Proxy(Box::new(ProxyRoute::new(target))))
+    }
+
+    pub fn path<'a>(&'a self, name: &'a str) -> &'a str {
+        self.path.as_deref().unwrap_or(name)
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority.unwrap_or(0)
+    }
+}
+
+/// Accepts both the original `"prefix": "target"` form and the full route object.
+fn deserialize_routes<'de, D>(deserializer: D) -> Result<HashMap<String, Route>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    // Not an untagged enum: that would replace every error inside a route
+    // object with "did not match any variant".
+    let entries: HashMap<String, serde_json::Value> = HashMap::deserialize(deserializer)?;
+    entries
+        .into_iter()
+        .map(|(prefix, entry)| {
+            let route = match entry {
+                serde_json::Value::String(target) => Route::proxy(target),
+                entry => Route::deserialize(entry)
+                    .map_err(|e| D::Error::custom(format!("route {}: {}", prefix, e)))?,
+            };
+            Ok((prefix, route))
+        })
+        .collect()
+}
+
+/// Reads a route's action, treating a missing `type` as `proxy` so routes
+/// written before route types existed keep working.
+fn deserialize_action<'de, D>(deserializer: D) -> Result<RouteAction, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let mut fields = serde_json::Map::deserialize(deserializer)?;
+    fields
+        .entry("type")
+        .or_insert_with(|| serde_json::Value::from("proxy"));
+
+    RouteAction::deserialize(serde_json::Value::Object(fields)).map_err(D::Error::custom)
+}
+
+pub fn get_config_path() -> PathBuf {
+    dirs::config_dir()
+        .expect("Failed to find config directory")
+        .join("prin/config.json")
+}
+
+pub fn load_config() -> ProxyConfig {
+    let config_path = get_config_path();
+    if !config_path.exists() {
+        let default_config = ProxyConfig {
+            routes: HashMap::new(),
+            fallback: Fallback::default(),
+            retry_budget: RetryBudget::default(),
+            timeouts: Timeouts::default(),
+            pool: ConnectionPool::default(),
+            dns: DnsConfig::default(),
+            upstream_proxy: None,
+            error_pages: HashMap::new(),
+        };
+
+        if let Some(config_dir) = config_path.parent() {
+            fs::create_dir_all(config_dir).expect("Failed to create config directory");
+        }
+
+        let config_data = serde_json::to_string_pretty(&default_config)
+            .expect("Failed to serialize default config");
+        fs::write(&config_path, config_data).expect("Failed to write default config file");
+
+        println!(
+            "{}",
+            format!("✅ Created new config file at {:?}", config_path).green()
+        );
+        return default_config;
+    }
+
+    let config_data = fs::read_to_string(&config_path)
+        .unwrap_or_else(|_| panic!("Failed to read config file at {:?}", config_path));
+
+    println!("{}", "✅ Loaded configuration.".green());
+    serde_json::from_str(&config_data).expect("Invalid config format")
+}
+
+pub fn save_config(config: &ProxyConfig) {
+    let config_path = get_config_path();
+    let config_dir = config_path.parent().unwrap();
+    fs::create_dir_all(config_dir).expect("Failed to create config directory");
+    let config_data = serde_json::to_string_pretty(config).expect("Failed to serialize config");
+    fs::write(config_path, config_data).expect("Failed to write config file");
+
+    println!("{}", "💾 Configuration saved.".blue());
+}
@@ -0,0 +1,524 @@
+use hyper::client::connect::{Connected, Connection};
+use hyper::client::HttpConnector;
+use hyper::http::uri::Scheme;
+use hyper::service::Service;
+use hyper::Uri;
+use rustls::ServerName;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+use crate::config::ConnectionPool;
+use crate::dns::{Dns, Resolver};
+use crate::pool::{Pools, Slot};
+use crate::tls::TlsSettings;
+use crate::upstream_proxy::ForwardProxy;
+
+/// Scheme of the URIs that address a Unix socket. Their host is the
+/// socket's path, hex-encoded so that any path makes a valid authority.
+const UNIX_SCHEME: &str = "unix";
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Rewrites a `unix:/run/app.sock` target, optionally followed by a path as
+/// in `unix:/run/app.sock:/v1`, into a URI the connector understands.
+/// Returns `None` for targets that don't start with `unix:`.
+pub fn unix_url(target: &str) -> Option<String> {
+    let rest = target.strip_prefix("unix:")?;
+    let (socket, path) = match rest.split_once(":/") {
+        Some((socket, path)) => (socket, format!("/{}", path)),
+        None => (rest, String::new()),
+    };
+    let host: String = socket.bytes().map(|byte| format!("{:02x}", byte)).collect();
+    Some(format!("{}://{}{}", UNIX_SCHEME, host, path))
+}
+
+/// The socket path a `unix://` URI from `unix_url` points at, or `None`
+/// for other URIs.
+pub fn socket_path(uri: &Uri) -> Option<PathBuf> {
+    if uri.scheme_str() != Some(UNIX_SCHEME) {
+        return None;
+    }
+    let host = uri.host()?;
+    let bytes = (0..host.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(host.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// Opens upstream connections: TCP for `http://` URIs, TLS over TCP for
+/// `https://` ones, and Unix sockets for the `unix://` URIs that `unix:`
+/// targets are turned into. Connections are counted per host in `pools`,
+/// which all connectors share.
+/// Host names are resolved through `Dns`. TCP connections go through the
+/// forward `proxy` if there is one, unless `NO_PROXY` covers the host.
+#[derive(Clone)]
+pub struct Connector {
+    http: HttpConnector<Resolver>,
+    tls: TlsSettings,
+    connect_timeout: Option<Duration>,
+    pools: Arc<Pools>,
+    proxy: Option<Arc<ForwardProxy>>,
+}
+
+impl Connector {
+    pub fn new(
+        connect_timeout: Option<Duration>,
+        tls: TlsSettings,
+        pool: &ConnectionPool,
+        pools: Arc<Pools>,
+        dns: Arc<Dns>,
+        proxy: Option<ForwardProxy>,
+    ) -> Self {
+        let mut http = HttpConnector::new_with_resolver(Resolver(dns));
+        http.set_connect_timeout(connect_timeout);
+        http.enforce_http(false);
+        http.set_nodelay(pool.tcp_nodelay);
+        http.set_keepalive(pool.tcp_keepalive_ms.map(Duration::from_millis));
+        Connector {
+            http,
+            tls,
+            connect_timeout,
+            pools,
+            proxy: proxy.map(Arc::new),
+        }
+    }
+
+    pub fn pools(&self) -> &Pools {
+        &self.pools
+    }
+
+    pub fn proxy(&self) -> Option<&ForwardProxy> {
+        self.proxy.as_deref()
+    }
+
+    /// The forward proxy connections to `uri` go through, if any.
+    pub fn proxy_for(&self, uri: &Uri) -> Option<&Arc<ForwardProxy>> {
+        if uri.scheme_str() == Some(UNIX_SCHEME) {
+            return None;
+        }
+        self.proxy.as_ref().filter(|proxy| proxy.applies_to(uri))
+    }
+
+    /// Opens the connection itself, without counting it.
+    fn connect(&mut self, uri: Uri) -> Pin<Box<dyn Future<Output = Result<Io, BoxError>> + Send>> {
+        if let Some(path) = socket_path(&uri) {
+            let timeout = self.connect_timeout;
+            return Box::pin(async move {
+                let stream = with_timeout(timeout, UnixStream::connect(path)).await?;
+                Ok(Io::Unix(stream))
+            });
+        }
+        if uri.scheme_str() == Some(UNIX_SCHEME) {
+            let invalid = io::Error::new(io::ErrorKind::InvalidInput, "invalid unix socket URI");
+            return Box::pin(async move { Err(invalid.into()) });
+        }
+
+        let proxy = self.proxy_for(&uri).cloned();
+        if uri.scheme() == Some(&Scheme::HTTPS) {
+            let server_name = match self.tls.server_name.clone() {
+                Some(server_name) => Ok(server_name),
+                None => server_name(&uri),
+            };
+            let tls = TlsConnector::from(self.tls.config.clone());
+            let timeout = self.connect_timeout;
+            let connecting = match &proxy {
+                Some(proxy) => self.http.call(proxy.uri().clone()),
+                None => self.http.call(uri.clone()),
+            };
+            return Box::pin(async move {
+                let server_name = server_name?;
+                let mut tcp = connecting.await?;
+                // Setting up the tunnel and the handshake count toward the
+                // connect timeout too.
+                let stream = with_timeout(timeout, async {
+                    if let Some(proxy) = &proxy {
+                        proxy.tunnel(&mut tcp, &uri).await?;
+                    }
+                    tls.connect(server_name, tcp).await
+                })
+                .await?;
+                Ok(Io::Tls(Box::new(stream)))
+            });
+        }
+
+        // Plain HTTP goes to the proxy as is; hyper sends it absolute-form
+        // requests since the connection is marked as proxied.
+        let connecting = match proxy {
+            Some(proxy) => self.http.call(proxy.uri().clone()),
+            None => self.http.call(uri),
+        };
+        Box::pin(async move { Ok(Io::Tcp(connecting.await?)) })
+    }
+}
+
+/// Runs `connect`, failing with `TimedOut` once `timeout` has passed.
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    connect: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, connect)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))?,
+        None => connect.await,
+    }
+}
+
+impl Service<Uri> for Connector {
+    type Response = Stream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Stream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let host = self.pools.host(&uri);
+        let proxied = uri.scheme() == Some(&Scheme::HTTP) && self.proxy_for(&uri).is_some();
+        let connecting = self.connect(uri);
+        Box::pin(async move {
+            // Waiting for room under `max_connections_per_host` doesn't
+            // count toward the connect timeout.
+            let slot = host.open().await;
+            Ok(Stream {
+                io: connecting.await?,
+                proxied,
+                _slot: slot,
+            })
+        })
+    }
+}
+
+/// The name to send as SNI and verify the certificate against: the URI's
+/// host, without the brackets around IPv6 addresses.
+fn server_name(uri: &Uri) -> io::Result<ServerName> {
+    let host = uri.host().unwrap_or_default();
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid TLS server name {}", host),
+        )
+    })
+}
+
+/// A connection to an upstream, counted as open in its host's pool.
+pub struct Stream {
+    io: Io,
+    /// Whether this is a connection to a forward proxy that gets plain HTTP
+    /// requests for the upstream.
+    proxied: bool,
+    _slot: Slot,
+}
+
+enum Io {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Unix(UnixStream),
+}
+
+impl Connection for Stream {
+    fn connected(&self) -> Connected {
+        let connected = match &self.io {
+            Io::Tcp(stream) => stream.connected(),
+            Io::Tls(stream) => {
+                let (tcp, tls) = stream.get_ref();
+                match tls.alpn_protocol() {
+                    Some(b"h2") => tcp.connected().negotiated_h2(),
+                    _ => tcp.connected(),
+                }
+            }
+            Io::Unix(_) => Connected::new(),
+        };
+        connected.proxy(self.proxied)
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().io {
+            Io::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Io::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            Io::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().io {
+            Io::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Io::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            Io::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().io {
+            Io::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Io::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            Io::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().io {
+            Io::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Io::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            Io::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DnsConfig, UpstreamProtocol, UpstreamTls};
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    /// A local TLS server whose certificate, signed by a throwaway CA, is
+    /// valid for `localhost` and `backend.test`. Every connection is told
+    /// the SNI name it sent. Returns the port and the CA file.
+    async fn tls_server(name: &str) -> (u16, PathBuf) {
+        let mut ca_params = CertificateParams::new(Vec::new());
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+        let cert = Certificate::from_params(CertificateParams::new(vec![
+            "localhost".to_string(),
+            "backend.test".to_string(),
+        ]))
+        .unwrap();
+
+        let ca_file =
+            std::env::temp_dir().join(format!("prin-{}-{}.pem", name, std::process::id()));
+        std::fs::write(&ca_file, ca.serialize_pem().unwrap()).unwrap();
+
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(
+                    cert.serialize_der_with_signer(&ca).unwrap(),
+                )],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(tcp).await else {
+                        return;
+                    };
+                    let sni = stream.get_ref().1.server_name().unwrap_or("-").to_string();
+                    let _ = stream.write_all(sni.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        (port, ca_file)
+    }
+
+    /// Connects to `uri` with `tls` and returns the SNI name the server saw.
+    async fn handshake(uri: &str, tls: Option<UpstreamTls>) -> Result<String, String> {
+        let tls = TlsSettings::new(tls.as_ref(), UpstreamProtocol::Http1)?;
+        let dns = Arc::new(Dns::new(&DnsConfig::default())?);
+        let mut connector = Connector::new(
+            Some(Duration::from_secs(5)),
+            tls,
+            &ConnectionPool::default(),
+            Arc::new(Pools::new(None)),
+            dns,
+            None,
+        );
+        let mut stream = connector
+            .call(uri.parse().unwrap())
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut sni = String::new();
+        stream
+            .read_to_string(&mut sni)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(sni)
+    }
+
+    #[tokio::test]
+    async fn verifies_against_ca_file() {
+        let (port, ca_file) = tls_server("ca-file").await;
+        let tls = UpstreamTls {
+            ca_file: Some(ca_file.display().to_string()),
+            ..UpstreamTls::default()
+        };
+        let sni = handshake(&format!("https://localhost:{}", port), Some(tls)).await;
+        assert_eq!(sni.unwrap(), "localhost");
+    }
+
+    #[tokio::test]
+    async fn system_roots_reject_unknown_ca() {
+        let (port, _) = tls_server("system-roots").await;
+        let error = handshake(&format!("https://localhost:{}", port), None)
+            .await
+            .unwrap_err();
+        assert!(error.contains("UnknownIssuer"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn server_name_overrides_sni_and_verification() {
+        let (port, ca_file) = tls_server("server-name").await;
+        let uri = format!("https://127.0.0.1:{}", port);
+        let tls = UpstreamTls {
+            ca_file: Some(ca_file.display().to_string()),
+            ..UpstreamTls::default()
+        };
+
+        // The certificate is not valid for the IP address itself.
+        let error = handshake(&uri, Some(tls.clone())).await.unwrap_err();
+        assert!(error.contains("NotValidForName"), "{}", error);
+
+        let tls = UpstreamTls {
+            server_name: Some("backend.test".to_string()),
+            ..tls
+        };
+        assert_eq!(handshake(&uri, Some(tls)).await.unwrap(), "backend.test");
+    }
+
+    #[tokio::test]
+    async fn insecure_skip_verify_accepts_unknown_ca() {
+        let (port, _) = tls_server("insecure").await;
+        let tls = UpstreamTls {
+            insecure_skip_verify: true,
+            ..UpstreamTls::default()
+        };
+        let sni = handshake(&format!("https://localhost:{}", port), Some(tls)).await;
+        assert_eq!(sni.unwrap(), "localhost");
+    }
+
+    #[tokio::test]
+    async fn reloaded_client_certificate_is_presented() {
+        let ca = Certificate::from_params({
+            let mut params = CertificateParams::new(Vec::new());
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+        })
+        .unwrap();
+        let server =
+            Certificate::from_params(CertificateParams::new(vec!["localhost".to_string()]))
+                .unwrap();
+        let clients = [1, 2].map(|n| {
+            Certificate::from_params(CertificateParams::new(vec![format!("client{}", n)])).unwrap()
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(&rustls::Certificate(ca.serialize_der().unwrap()))
+            .unwrap();
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(
+                rustls::server::AllowAnyAuthenticatedClient::new(roots).boxed(),
+            )
+            .with_single_cert(
+                vec![rustls::Certificate(
+                    server.serialize_der_with_signer(&ca).unwrap(),
+                )],
+                rustls::PrivateKey(server.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Every connection is sent the client certificate it presented.
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let Ok(mut stream) = acceptor.clone().accept(tcp).await else {
+                    continue;
+                };
+                let presented = stream.get_ref().1.peer_certificates().unwrap()[0].clone();
+                let _ = stream.write_all(&presented.0).await;
+                let _ = stream.shutdown().await;
+            }
+        });
+
+        let dir = std::env::temp_dir().join(format!("prin-mtls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (ca_file, cert_file, key_file) = (
+            dir.join("ca.pem"),
+            dir.join("client.pem"),
+            dir.join("client.key"),
+        );
+        std::fs::write(&ca_file, ca.serialize_pem().unwrap()).unwrap();
+        let install = |client: &Certificate| {
+            std::fs::write(&cert_file, client.serialize_pem_with_signer(&ca).unwrap()).unwrap();
+            std::fs::write(&key_file, client.serialize_private_key_pem()).unwrap();
+        };
+        // Signatures differ on every signing, so certificates are told apart
+        // by their public key.
+        let holds_key = |der: &[u8], client: &Certificate| {
+            let key = client.get_key_pair().public_key_raw();
+            der.windows(key.len()).any(|window| window == key)
+        };
+        install(&clients[0]);
+
+        let tls = UpstreamTls {
+            ca_file: Some(ca_file.display().to_string()),
+            client_cert: Some(cert_file.display().to_string()),
+            client_key: Some(key_file.display().to_string()),
+            ..UpstreamTls::default()
+        };
+        let mut connector = Connector::new(
+            Some(Duration::from_secs(5)),
+            TlsSettings::new(Some(&tls), UpstreamProtocol::Http1).unwrap(),
+            &ConnectionPool::default(),
+            Arc::new(Pools::new(None)),
+            Arc::new(Dns::new(&DnsConfig::default()).unwrap()),
+            None,
+        );
+        let uri: Uri = format!("https://localhost:{}", port).parse().unwrap();
+        let mut presented = || {
+            let connecting = connector.call(uri.clone());
+            async move {
+                let mut der = Vec::new();
+                connecting
+                    .await
+                    .unwrap()
+                    .read_to_end(&mut der)
+                    .await
+                    .unwrap();
+                der
+            }
+        };
+
+        assert!(holds_key(&presented().await, &clients[0]));
+        install(&clients[1]);
+        // Files are checked for changes at most once per RELOAD_CHECK.
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(holds_key(&presented().await, &clients[1]));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
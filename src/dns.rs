@@ -0,0 +1,537 @@
+use colored::*;
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::service::Service;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+use crate::balancer::Balancer;
+use crate::config::DnsConfig;
+use crate::health;
+use crate::router::Router;
+use crate::upstream::UpstreamClient;
+
+/// How long to wait before asking again after a failed lookup.
+const RETRY_AFTER_ERROR: Duration = Duration::from_secs(5);
+
+/// Shortest pause between two lookups of an SRV record.
+const MIN_REFRESH: Duration = Duration::from_secs(1);
+
+/// How long answers from the system resolver are used. It does not tell
+/// their TTL.
+const SYSTEM_TTL: Duration = Duration::from_secs(30);
+
+/// Resolves upstream host names for all routes. Answers are cached until
+/// their TTL runs out, then refreshed in the background while the old
+/// answer stays in use, so connections never wait on a slow or unreachable
+/// name server for a host resolved before.
+///
+/// Without configured `nameservers`, host names go through the system
+/// resolver like in any other program, `/etc/hosts` included.
+pub struct Dns {
+    /// Asks the configured name servers, if there are any.
+    nameservers: Option<TokioAsyncResolver>,
+    /// Asks the system's name servers for SRV records, which the system
+    /// resolver can't look up. Only set up once an `srv` route needs it.
+    system: OnceLock<Result<TokioAsyncResolver, String>>,
+    ttl: Option<Duration>,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+struct Cached {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+    /// Whether a background lookup is already under way.
+    refreshing: bool,
+}
+
+/// The upstreams an SRV record points at, and until when they are valid.
+pub struct Discovered {
+    pub urls: Vec<(String, u32)>,
+    pub valid_until: Instant,
+}
+
+impl Dns {
+    pub fn new(config: &DnsConfig) -> Result<Self, String> {
+        let nameservers = match config.nameservers.is_empty() {
+            true => None,
+            false => Some(nameserver_resolver(&config.nameservers)?),
+        };
+        Ok(Dns {
+            nameservers,
+            system: OnceLock::new(),
+            ttl: config.ttl_ms.map(Duration::from_millis),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The resolver for SRV records: the configured name servers, or else
+    /// the system's, set up on first use.
+    fn srv_resolver(&self) -> Result<&TokioAsyncResolver, String> {
+        if let Some(resolver) = &self.nameservers {
+            return Ok(resolver);
+        }
+        self.system
+            .get_or_init(|| {
+                let (config, options) = hickory_resolver::system_conf::read_system_conf()
+                    .map_err(|e| format!("cannot read the system's resolver config: {}", e))?;
+                Ok(hickory(config, options))
+            })
+            .as_ref()
+            .map_err(Clone::clone)
+    }
+
+    /// The addresses of `host`. Only waits for the name servers the first
+    /// time `host` is looked up.
+    pub async fn lookup(self: &Arc<Self>, host: &str) -> io::Result<Vec<IpAddr>> {
+        {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(cached) = cache.get_mut(host) {
+                self.refresh_if_expired(host, cached);
+                return Ok(cached.addrs.clone());
+            }
+        }
+        self.resolve(host).await
+    }
+
+    /// Whether `ip` is still an address of `host`, judged by the cached
+    /// answer. An expired answer is refreshed in the background, so a moved
+    /// host is noticed by the next request.
+    pub fn is_current(self: &Arc<Self>, host: &str, ip: IpAddr) -> bool {
+        if host
+            .trim_matches(|c| c == '[' || c == ']')
+            .parse::<IpAddr>()
+            .is_ok()
+        {
+            return true;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        let Some(cached) = cache.get_mut(host) else {
+            return true;
+        };
+        self.refresh_if_expired(host, cached);
+        cached.addrs.contains(&ip)
+    }
+
+    fn refresh_if_expired(self: &Arc<Self>, host: &str, cached: &mut Cached) {
+        if cached.expires > Instant::now() || cached.refreshing {
+            return;
+        }
+        cached.refreshing = true;
+        let dns = Arc::clone(self);
+        let host = host.to_string();
+        tokio::spawn(async move {
+            let _ = dns.resolve(&host).await;
+        });
+    }
+
+    /// Asks the name servers for `host` and caches the answer. Falls back
+    /// to an expired answer if they fail.
+    async fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let result = match &self.nameservers {
+            Some(resolver) => resolver
+                .lookup_ip(host)
+                .await
+                .map(|lookup| (lookup.iter().collect(), lookup.valid_until()))
+                .map_err(|e| e.to_string()),
+            None => lookup_system(host).await,
+        };
+        let mut cache = self.cache.lock().unwrap();
+        match result {
+            Ok((addrs, valid_until)) => {
+                let expires = self.expires(valid_until);
+                if let Some(old) = cache.get(host).filter(|old| old.addrs != addrs) {
+                    println!(
+                        "{}",
+                        format!(
+                            "🧭 {} moved from {} to {}",
+                            host,
+                            list(&old.addrs),
+                            list(&addrs)
+                        )
+                        .cyan()
+                    );
+                }
+                cache.insert(
+                    host.to_string(),
+                    Cached {
+                        addrs: addrs.clone(),
+                        expires,
+                        refreshing: false,
+                    },
+                );
+                Ok(addrs)
+            }
+            Err(error) => match cache.get_mut(host) {
+                Some(stale) => {
+                    eprintln!(
+                        "{}",
+                        format!(
+                            "❌ Could not resolve {}, using the last answer: {}",
+                            host, error
+                        )
+                        .red()
+                    );
+                    stale.refreshing = false;
+                    stale.expires = Instant::now() + RETRY_AFTER_ERROR;
+                    Ok(stale.addrs.clone())
+                }
+                None => Err(io::Error::new(io::ErrorKind::NotFound, error)),
+            },
+        }
+    }
+
+    /// Looks up the upstreams of an `srv` route. Only the records with the
+    /// best (lowest) priority are used; their weights become the upstreams'
+    /// weights.
+    pub async fn discover(&self, srv: &str) -> Result<Discovered, String> {
+        let (scheme, name, path) = parse_srv(srv)?;
+        let lookup = self
+            .srv_resolver()?
+            .srv_lookup(name)
+            .await
+            .map_err(|e| e.to_string())?;
+        let best = lookup.iter().map(|record| record.priority()).min();
+        let mut urls: Vec<(String, u32)> = lookup
+            .iter()
+            .filter(|record| Some(record.priority()) == best)
+            .map(|record| {
+                let host = record.target().to_utf8();
+                let url = format!(
+                    "{}://{}:{}{}",
+                    scheme,
+                    host.trim_end_matches('.'),
+                    record.port(),
+                    path
+                );
+                (url, u32::from(record.weight()).max(1))
+            })
+            .collect();
+        urls.sort();
+        Ok(Discovered {
+            urls,
+            valid_until: self.expires(lookup.as_lookup().valid_until()),
+        })
+    }
+
+    fn expires(&self, valid_until: Instant) -> Instant {
+        match self.ttl {
+            Some(ttl) => Instant::now() + ttl,
+            None => valid_until,
+        }
+    }
+}
+
+/// A resolver asking only `nameservers`, each given as `ip` or `ip:port`.
+fn nameserver_resolver(nameservers: &[String]) -> Result<TokioAsyncResolver, String> {
+    let mut config = ResolverConfig::new();
+    for nameserver in nameservers {
+        let addr = nameserver
+            .parse::<SocketAddr>()
+            .or_else(|_| {
+                nameserver
+                    .parse::<IpAddr>()
+                    .map(|ip| SocketAddr::new(ip, 53))
+            })
+            .map_err(|_| format!("dns: invalid nameserver {}", nameserver))?;
+        for server in
+            NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true).into_inner()
+        {
+            config.add_name_server(server);
+        }
+    }
+    Ok(hickory(config, ResolverOpts::default()))
+}
+
+fn hickory(config: ResolverConfig, mut options: ResolverOpts) -> TokioAsyncResolver {
+    // Answers are cached in `Dns`, where `ttl_ms` can apply to them.
+    options.cache_size = 0;
+    TokioAsyncResolver::tokio(config, options)
+}
+
+/// Looks `host` up with the system resolver (`getaddrinfo`).
+async fn lookup_system(host: &str) -> Result<(Vec<IpAddr>, Instant), String> {
+    let name = Name::from_str(host).map_err(|e| e.to_string())?;
+    let addrs = GaiResolver::new()
+        .call(name)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut ips: Vec<IpAddr> = Vec::new();
+    for addr in addrs {
+        if !ips.contains(&addr.ip()) {
+            ips.push(addr.ip());
+        }
+    }
+    Ok((ips, Instant::now() + SYSTEM_TTL))
+}
+
+/// Splits an `srv` setting like `https://_api._tcp.example.internal/v1`
+/// into scheme, record name and base path.
+pub fn parse_srv(srv: &str) -> Result<(&str, &str, &str), String> {
+    let (scheme, rest) = srv
+        .split_once("://")
+        .ok_or_else(|| format!("srv {} needs a scheme, e.g. http://{}", srv, srv))?;
+    if scheme != "http" && scheme != "https" {
+        return Err(format!("srv {}: scheme must be http or https", srv));
+    }
+    let (name, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, ""),
+    };
+    if name.is_empty() {
+        return Err(format!("srv {} has no record name", srv));
+    }
+    Ok((scheme, name, path.trim_end_matches('/')))
+}
+
+fn list(addrs: &[IpAddr]) -> String {
+    let addrs: Vec<String> = addrs.iter().map(IpAddr::to_string).collect();
+    addrs.join(", ")
+}
+
+/// Resolves host names for the connector through `Dns`.
+#[derive(Clone)]
+pub struct Resolver(pub Arc<Dns>);
+
+impl Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let dns = Arc::clone(&self.0);
+        Box::pin(async move {
+            let addrs = dns.lookup(name.as_str()).await?;
+            // The connector fills in the port.
+            let addrs: Vec<SocketAddr> = addrs.into_iter().map(|ip| (ip, 0).into()).collect();
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+/// Starts one background task per `srv` route that looks its record up
+/// again whenever the answer expires, and waits until each has done its
+/// first lookup. Upstreams discovered later are health checked like the
+/// others.
+pub async fn start(router: &Router) {
+    let mut first_lookups = Vec::new();
+    for (balancer, client) in router.proxies() {
+        let Some(srv) = balancer.srv() else {
+            continue;
+        };
+        let (done, first_lookup) = oneshot::channel();
+        let srv = srv.to_string();
+        let dns = Arc::clone(router.dns());
+        let balancer = Arc::clone(balancer);
+        let client = Arc::clone(client);
+        tokio::spawn(async move { watch(dns, srv, balancer, client, done).await });
+        first_lookups.push(first_lookup);
+    }
+    for first_lookup in first_lookups {
+        let _ = first_lookup.await;
+    }
+}
+
+async fn watch(
+    dns: Arc<Dns>,
+    srv: String,
+    balancer: Arc<Balancer>,
+    client: Arc<UpstreamClient>,
+    done: oneshot::Sender<()>,
+) {
+    let mut done = Some(done);
+    loop {
+        let next = match dns.discover(&srv).await {
+            Ok(discovered) if !discovered.urls.is_empty() => {
+                let added = balancer.set_upstreams(discovered.urls);
+                // The first set is probed by the startup health check.
+                if let (Some(check), None) = (balancer.health_check(), &done) {
+                    for upstream in added {
+                        health::spawn_for(check.clone(), upstream, Arc::clone(&client));
+                    }
+                }
+                discovered.valid_until
+            }
+            Ok(_) => {
+                eprintln!("{}", format!("❌ SRV {}: no records", srv).red());
+                Instant::now() + RETRY_AFTER_ERROR
+            }
+            Err(error) => {
+                eprintln!("{}", format!("❌ SRV {}: {}", srv, error).red());
+                Instant::now() + RETRY_AFTER_ERROR
+            }
+        };
+        if let Some(done) = done.take() {
+            let _ = done.send(());
+        }
+        // Records with a TTL of 0 would otherwise be looked up in a loop.
+        let next = next.max(Instant::now() + MIN_REFRESH);
+        tokio::time::sleep_until(next.into()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::{Message, MessageType, ResponseCode};
+    use hickory_proto::rr::rdata::{A, SRV};
+    use hickory_proto::rr::{Name, RData, Record, RecordType};
+    use std::net::Ipv4Addr;
+    use tokio::net::UdpSocket;
+
+    /// What the stub name server answers: A records, and SRV records as
+    /// `(priority, weight, port, target)`.
+    #[derive(Default)]
+    struct Zone {
+        a: HashMap<String, Ipv4Addr>,
+        srv: HashMap<String, Vec<(u16, u16, u16, String)>>,
+    }
+
+    /// Serves `zone` over UDP on a free local port and returns the port.
+    async fn stub_resolver(zone: Arc<Mutex<Zone>>) -> u16 {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buffer = [0; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buffer).await {
+                let Ok(request) = Message::from_vec(&buffer[..len]) else {
+                    continue;
+                };
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_desired(true)
+                    .set_recursion_available(true);
+                for query in request.queries() {
+                    response.add_query(query.clone());
+                    let name = query.name().to_utf8();
+                    let name = name.trim_end_matches('.');
+                    let zone = zone.lock().unwrap();
+                    let rdata: Vec<RData> = match query.query_type() {
+                        RecordType::A => zone
+                            .a
+                            .get(name)
+                            .map(|ip| RData::A(A(*ip)))
+                            .into_iter()
+                            .collect(),
+                        RecordType::SRV => zone
+                            .srv
+                            .get(name)
+                            .into_iter()
+                            .flatten()
+                            .map(|(priority, weight, port, target)| {
+                                let target = Name::from_ascii(target).unwrap();
+                                RData::SRV(SRV::new(*priority, *weight, *port, target))
+                            })
+                            .collect(),
+                        _ => Vec::new(),
+                    };
+                    if !zone.a.contains_key(name) && !zone.srv.contains_key(name) {
+                        response.set_response_code(ResponseCode::NXDomain);
+                    }
+                    for rdata in rdata {
+                        response.add_answer(Record::from_rdata(query.name().clone(), 300, rdata));
+                    }
+                }
+                let _ = socket.send_to(&response.to_vec().unwrap(), from).await;
+            }
+        });
+        port
+    }
+
+    fn dns(port: u16, ttl_ms: Option<u64>) -> Arc<Dns> {
+        let config = DnsConfig {
+            nameservers: vec![format!("127.0.0.1:{}", port)],
+            ttl_ms,
+        };
+        Arc::new(Dns::new(&config).unwrap())
+    }
+
+    #[tokio::test]
+    async fn system_resolver_answers_without_nameservers() {
+        let dns = Arc::new(Dns::new(&DnsConfig::default()).unwrap());
+        assert!(dns.system.get().is_none());
+        assert_eq!(
+            dns.lookup("127.0.0.1").await.unwrap(),
+            vec![IpAddr::from([127, 0, 0, 1])]
+        );
+        assert!(!dns.lookup("localhost").await.unwrap().is_empty());
+        // Plain lookups never read the system's name server config.
+        assert!(dns.system.get().is_none());
+    }
+
+    #[tokio::test]
+    async fn discover_keeps_lowest_priority_with_weights() {
+        let mut zone = Zone::default();
+        zone.srv.insert(
+            "_api._tcp.test".to_string(),
+            vec![
+                (10, 5, 4001, "a.test.".to_string()),
+                (10, 0, 4002, "b.test.".to_string()),
+                (20, 9, 4003, "backup.test.".to_string()),
+            ],
+        );
+        let port = stub_resolver(Arc::new(Mutex::new(zone))).await;
+
+        let discovered = dns(port, None)
+            .discover("http://_api._tcp.test/v1")
+            .await
+            .unwrap();
+        assert_eq!(
+            discovered.urls,
+            vec![
+                ("http://a.test:4001/v1".to_string(), 5),
+                // A weight of 0 still gets some traffic.
+                ("http://b.test:4002/v1".to_string(), 1),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn lookup_picks_up_changed_record_after_ttl() {
+        let zone = Arc::new(Mutex::new(Zone::default()));
+        let old = Ipv4Addr::new(127, 0, 0, 1);
+        let new = Ipv4Addr::new(127, 0, 0, 2);
+        zone.lock().unwrap().a.insert("api.test".to_string(), old);
+        let port = stub_resolver(Arc::clone(&zone)).await;
+        let dns = dns(port, Some(100));
+
+        assert_eq!(
+            dns.lookup("api.test").await.unwrap(),
+            vec![IpAddr::from(old)]
+        );
+        zone.lock().unwrap().a.insert("api.test".to_string(), new);
+        // Still fresh: answered from the cache.
+        assert_eq!(
+            dns.lookup("api.test").await.unwrap(),
+            vec![IpAddr::from(old)]
+        );
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        // Expired: the old answer is served while it is refreshed.
+        assert_eq!(
+            dns.lookup("api.test").await.unwrap(),
+            vec![IpAddr::from(old)]
+        );
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while dns.lookup("api.test").await.unwrap() != vec![IpAddr::from(new)] {
+            assert!(Instant::now() < deadline, "changed record never picked up");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!dns.is_current("api.test", IpAddr::from(old)));
+    }
+}
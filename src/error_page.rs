@@ -0,0 +1,184 @@
+use colored::*;
+use hyper::header::{HeaderValue, ACCEPT, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use std::collections::HashMap;
+
+/// The format a client prefers for error pages, judged by its `Accept`.
+#[derive(Clone, Copy)]
+pub enum Format {
+    Html,
+    Json,
+}
+
+impl Format {
+    /// JSON if the client ranks it above HTML, HTML otherwise.
+    pub fn of(req: &Request<Body>) -> Self {
+        let Some(accept) = req.headers().get(ACCEPT).and_then(|v| v.to_str().ok()) else {
+            return Format::Html;
+        };
+
+        let (mut html, mut json) = (0.0, 0.0);
+        for entry in accept.split(',') {
+            let mut params = entry.split(';');
+            let media = params.next().unwrap_or_default().trim().to_lowercase();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            match media.as_str() {
+                "text/html" => html = f32::max(html, quality),
+                "application/json" => json = f32::max(json, quality),
+                _ if media.starts_with("application/") && media.ends_with("+json") => {
+                    json = f32::max(json, quality)
+                }
+                _ => {}
+            }
+        }
+
+        match json > html {
+            true => Format::Json,
+            false => Format::Html,
+        }
+    }
+}
+
+/// Builds an error response. A template configured for `status` in
+/// `error_pages` wins over the built-in pages; it can use `{status}`,
+/// `{title}`, `{reason}` and `{route}`.
+pub async fn respond(
+    status: StatusCode,
+    route: Option<&str>,
+    reason: &str,
+    format: Format,
+    pages: &HashMap<u16, String>,
+) -> Response<Body> {
+    let title = status.canonical_reason().unwrap_or_default();
+
+    if let Some(template) = pages.get(&status.as_u16()) {
+        match tokio::fs::read_to_string(template).await {
+            Ok(page) => {
+                let content_type = mime_guess::from_path(template).first_or_text_plain();
+                let escape = |text: &str| match content_type.subtype() == mime_guess::mime::HTML {
+                    true => html_escape(text),
+                    false => text.to_string(),
+                };
+                let values = [
+                    ("status", status.as_str().to_string()),
+                    ("title", title.to_string()),
+                    ("reason", escape(reason)),
+                    ("route", escape(route.unwrap_or_default())),
+                ];
+                let mut response = Response::new(Body::from(fill(&page, &values)));
+                *response.status_mut() = status;
+                if let Ok(value) = HeaderValue::from_str(content_type.as_ref()) {
+                    response.headers_mut().insert(CONTENT_TYPE, value);
+                }
+                return response;
+            }
+            Err(error) => eprintln!("{}", format!("❌ Error page {}: {}", template, error).red()),
+        }
+    }
+
+    let (content_type, body) = match format {
+        Format::Json => {
+            let mut body = serde_json::json!({
+                "status": status.as_u16(),
+                "error": title,
+                "reason": reason,
+            });
+            if let Some(route) = route {
+                body["route"] = route.into();
+            }
+            ("application/json", format!("{}\n", body))
+        }
+        Format::Html => {
+            let route = route
+                .map(|route| format!("<p>Route: <code>{}</code></p>\n", html_escape(route)))
+                .unwrap_or_default();
+            let body = format!(
+                "<!DOCTYPE html>\n<html>\n<head><title>{status} {title}</title></head>\n<body>\n\
+                 <h1>{status} {title}</h1>\n<p>{reason}</p>\n{route}</body>\n</html>\n",
+                status = status.as_str(),
+                title = title,
+                reason = html_escape(reason),
+                route = route,
+            );
+            ("text/html; charset=utf-8", body)
+        }
+    };
+
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Checks the configured templates before the first request arrives.
+pub fn check(pages: &HashMap<u16, String>) -> Result<(), String> {
+    for status in pages.keys() {
+        match StatusCode::from_u16(*status) {
+            Ok(code) if code.is_client_error() || code.is_server_error() => {}
+            _ => {
+                return Err(format!(
+                    "error pages are for 4xx and 5xx statuses, got {}",
+                    status
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fills the `{name}` placeholders in `page` with `values`. Everything else,
+/// unknown placeholders and `$` included, is left as it is, so pages can
+/// carry scripts and styles.
+fn fill(page: &str, values: &[(&str, String)]) -> String {
+    let mut out = String::with_capacity(page.len());
+    let mut rest = page;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let value = rest.split_once('}').and_then(|(name, after)| {
+            let (_, value) = values.iter().find(|(known, _)| *known == name)?;
+            Some((value, after))
+        });
+        match value {
+            Some((value, after)) => {
+                out.push_str(value);
+                rest = after;
+            }
+            None => out.push('{'),
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_only_replaces_known_placeholders() {
+        let values = [
+            ("status", "502".to_string()),
+            ("reason", "{route} is down".to_string()),
+            ("route", "api".to_string()),
+        ];
+        let page = "<p>{status}: {reason} ({route})</p>\n\
+                    <script>const price = `$price ${x}`; if (a) { b({}); } {nope}</script>";
+        assert_eq!(
+            fill(page, &values),
+            "<p>502: {route} is down (api)</p>\n\
+             <script>const price = `$price ${x}`; if (a) { b({}); } {nope}</script>"
+        );
+    }
+}
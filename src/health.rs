@@ -0,0 +1,143 @@
+use colored::*;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::balancer::Upstream;
+use crate::config::HealthCheck;
+use crate::rewrite::Target;
+use crate::router::Router;
+use crate::upstream::UpstreamClient;
+
+/// Probes every health-checked upstream once and records the result, so
+/// the startup listing already shows which upstreams are reachable.
+pub async fn check_all(router: &Router) {
+    let probes: Vec<_> = checked_upstreams(router)
+        .map(|(check, upstream, client)| {
+            let check = check.clone();
+            let client = Arc::clone(client);
+            tokio::spawn(async move {
+                let healthy = probe(&check, &client, &upstream.url).await.is_ok();
+                upstream.set_healthy(healthy);
+            })
+        })
+        .collect();
+    for probe in probes {
+        let _ = probe.await;
+    }
+}
+
+/// Starts one background task per health-checked upstream. Each probes its
+/// upstream every `interval_ms`, and takes it out of rotation after
+/// `unhealthy_threshold` failures in a row until `healthy_threshold` probes
+/// in a row pass again.
+pub fn spawn(router: &Router) {
+    for (check, upstream, client) in checked_upstreams(router) {
+        let check = check.clone();
+        let client = Arc::clone(client);
+        tokio::spawn(async move { watch(check, upstream, client).await });
+    }
+}
+
+/// Starts checking an upstream that service discovery added after startup:
+/// probes it once right away, then like the others until it is removed.
+pub fn spawn_for(check: HealthCheck, upstream: Arc<Upstream>, client: Arc<UpstreamClient>) {
+    tokio::spawn(async move {
+        let healthy = probe(&check, &client, &upstream.url).await.is_ok();
+        upstream.set_healthy(healthy);
+        watch(check, upstream, client).await
+    });
+}
+
+/// Every upstream with a health check, along with its check and the client
+/// of its route, so probes connect the same way requests do.
+fn checked_upstreams(
+    router: &Router,
+) -> impl Iterator<Item = (&HealthCheck, Arc<Upstream>, &Arc<UpstreamClient>)> {
+    router
+        .proxies()
+        .filter_map(|(balancer, client)| Some((balancer.health_check()?, balancer, client)))
+        .flat_map(|(check, balancer, client)| {
+            balancer
+                .upstreams()
+                .into_iter()
+                .map(move |upstream| (check, upstream, client))
+        })
+}
+
+async fn watch(check: HealthCheck, upstream: Arc<Upstream>, client: Arc<UpstreamClient>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(check.interval_ms.max(1)));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick fires right away; the upstream was just probed.
+    interval.tick().await;
+
+    let mut streak = 0;
+    loop {
+        interval.tick().await;
+        if upstream.is_removed() {
+            return;
+        }
+        let result = probe(&check, &client, &upstream.url).await;
+
+        // `streak` counts probes in a row that disagree with the current state.
+        let healthy = upstream.is_healthy();
+        if result.is_ok() == healthy {
+            streak = 0;
+            continue;
+        }
+        streak += 1;
+        let threshold = match healthy {
+            true => check.unhealthy_threshold,
+            false => check.healthy_threshold,
+        };
+        if streak < threshold.max(1) {
+            continue;
+        }
+
+        streak = 0;
+        upstream.set_healthy(!healthy);
+        match result {
+            Ok(()) => println!(
+                "{}",
+                format!("✅ Upstream {} is healthy again", upstream.url).green()
+            ),
+            Err(reason) => eprintln!(
+                "{}",
+                format!("❌ Upstream {} is unhealthy: {}", upstream.url, reason).red()
+            ),
+        }
+    }
+}
+
+/// Runs one probe against `url`, giving up after `timeout_ms`.
+async fn probe(check: &HealthCheck, client: &UpstreamClient, url: &str) -> Result<(), String> {
+    let timeout = Duration::from_millis(check.timeout_ms);
+    match tokio::time::timeout(timeout, run_probe(check, client, url)).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("no answer within {}ms", check.timeout_ms)),
+    }
+}
+
+async fn run_probe(check: &HealthCheck, client: &UpstreamClient, url: &str) -> Result<(), String> {
+    let target = Target::parse(url).map_err(|e| e.to_string())?;
+    let Some(path) = &check.path else {
+        let uri = target.uri("/", None).map_err(|e| e.to_string())?;
+        return client.connect(uri).await;
+    };
+
+    let path = match path.starts_with('/') {
+        true => path.clone(),
+        false => format!("/{}", path),
+    };
+    let uri = target.uri(&path, None).map_err(|e| e.to_string())?;
+    let response = client.get(uri).await.map_err(|e| e.to_string())?;
+
+    let status = response.status();
+    let expected = match check.expected_status {
+        Some(expected) => status.as_u16() == expected,
+        None => status.is_success(),
+    };
+    match expected {
+        true => Ok(()),
+        false => Err(format!("probe answered {}", status)),
+    }
+}
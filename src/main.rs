@@ -4,13 +4,39 @@ use dialoguer::theme::ColorfulTheme;
 use dialoguer::{Confirm, Input, Select};
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server, StatusCode};
-use serde::{Deserialize, Serialize};
+use hyper::Server;
 use std::collections::HashMap;
-use std::fs;
-use std::net::IpAddr;
 use std::sync::Arc;
-use std::{convert::Infallible, net::SocketAddr, path::PathBuf};
+use std::{convert::Infallible, net::SocketAddr};
+
+mod balancer;
+mod config;
+mod connector;
+mod dns;
+mod error_page;
+mod health;
+mod metrics;
+mod mock;
+mod pattern;
+mod pool;
+mod predicate;
+mod proxy;
+mod retry;
+mod rewrite;
+mod router;
+mod static_files;
+mod sticky;
+mod tls;
+mod upstream;
+mod upstream_proxy;
+
+use balancer::Balancer;
+use config::{
+    load_config, save_config, Fallback, MockRoute, ProxyConfig, RedirectRoute, Route, RouteAction,
+    StaticRoute,
+};
+use proxy::handle_request;
+use router::Router;
 
 #[derive(Parser)]
 #[command(name = "Prin")]
@@ -47,56 +73,6 @@ enum ConfigCommands {
     Delete,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct ProxyConfig {
-    routes: HashMap<String, String>,
-}
-
-fn get_config_path() -> PathBuf {
-    dirs::config_dir()
-        .expect("Failed to find config directory")
-        .join("prin/config.json")
-}
-
-fn load_config() -> ProxyConfig {
-    let config_path = get_config_path();
-    if !config_path.exists() {
-        let default_config = ProxyConfig {
-            routes: HashMap::new(),
-        };
-
-        if let Some(config_dir) = config_path.parent() {
-            fs::create_dir_all(config_dir).expect("Failed to create config directory");
-        }
-
-        let config_data = serde_json::to_string_pretty(&default_config)
-            .expect("Failed to serialize default config");
-        fs::write(&config_path, config_data).expect("Failed to write default config file");
-
-        println!(
-            "{}",
-            format!("✅ Created new config file at {:?}", config_path).green()
-        );
-        return default_config;
-    }
-
-    let config_data = fs::read_to_string(&config_path)
-        .unwrap_or_else(|_| panic!("Failed to read config file at {:?}", config_path));
-
-    println!("{}", "✅ Loaded configuration.".green());
-    serde_json::from_str(&config_data).expect("Invalid config format")
-}
-
-fn save_config(config: &ProxyConfig) {
-    let config_path = get_config_path();
-    let config_dir = config_path.parent().unwrap();
-    fs::create_dir_all(config_dir).expect("Failed to create config directory");
-    let config_data = serde_json::to_string_pretty(config).expect("Failed to serialize config");
-    fs::write(config_path, config_data).expect("Failed to write config file");
-
-    println!("{}", "💾 Configuration saved.".blue());
-}
-
 fn add_route(config: &mut ProxyConfig) -> Result<(), Box<dyn std::error::Error>> {
     println!("{}", "\n=== Adding New Route ===".yellow());
 
@@ -104,18 +80,99 @@ fn add_route(config: &mut ProxyConfig) -> Result<(), Box<dyn std::error::Error>>
         .with_prompt("🔗 Enter route prefix (e.g., /api)")
         .interact_text()?;
 
-    let target: String = Input::new()
-        .with_prompt("🎯 Enter target URL (e.g., http://localhost:3000)")
+    let host: String = Input::new()
+        .with_prompt("🌐 Enter host (optional, e.g., app.localhost or *.example.test)")
+        .allow_empty(true)
         .interact_text()?;
+    let host = host.trim().to_lowercase();
+
+    let route_types = ["proxy", "redirect", "static", "mock"];
+    let route_type = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("🧭 Select route type")
+        .items(&route_types)
+        .default(0)
+        .interact()?;
+
+    let mut route = match route_types[route_type] {
+        "redirect" => {
+            let location: String = Input::new()
+                .with_prompt("🔀 Enter redirect location (e.g., /dashboard{path}{query})")
+                .interact_text()?;
+            let status = Select::with_theme(&ColorfulTheme::default())
+                .with_prompt("🔢 Select redirect status")
+                .items(&RedirectRoute::STATUSES)
+                .default(1)
+                .interact()?;
+
+            Route::new(RouteAction::Redirect(RedirectRoute {
+                location,
+                status: RedirectRoute::STATUSES[status],
+            }))
+        }
+        "static" => {
+            let root: String = Input::new()
+                .with_prompt("📁 Enter directory to serve (e.g., ./dist)")
+                .interact_text()?;
+            let spa = Confirm::new()
+                .with_prompt("🧭 Serve index.html for unknown paths (single-page app)?")
+                .default(false)
+                .interact()?;
+
+            Route::new(RouteAction::Static(StaticRoute { root, spa }))
+        }
+        "mock" => {
+            let status: u16 = Input::new()
+                .with_prompt("🔢 Enter response status")
+                .default(200)
+                .interact_text()?;
+            let content_type: String = Input::new()
+                .with_prompt("🏷️ Enter content type (optional, e.g., application/json)")
+                .allow_empty(true)
+                .interact_text()?;
+            let body: String = Input::new()
+                .with_prompt("📝 Enter response body (optional)")
+                .allow_empty(true)
+                .interact_text()?;
+
+            let mut headers = HashMap::new();
+            if !content_type.is_empty() {
+                headers.insert("content-type".to_string(), content_type);
+            }
+            Route::new(RouteAction::Mock(MockRoute {
+                status,
+                headers,
+                body: (!body.is_empty()).then_some(body),
+                body_file: None,
+                template: false,
+            }))
+        }
+        _ => {
+            let target: String = Input::new()
+                .with_prompt(
+                    "🎯 Enter target URL (e.g., http://localhost:3000 or unix:/run/app.sock)",
+                )
+                .interact_text()?;
+            Route::proxy(target)
+        }
+    };
+    let target = route.action.summary();
+
+    let name = if host.is_empty() {
+        prefix
+    } else {
+        route.path = Some(prefix.clone());
+        route.host = Some(host.clone());
+        format!("{}{}", host, prefix)
+    };
 
     if Confirm::new()
-        .with_prompt(format!("⚡ Add route: {} → {}?", prefix, target))
+        .with_prompt(format!("⚡ Add route: {} → {}?", name, target))
         .interact()?
     {
-        config.routes.insert(prefix.clone(), target.clone());
+        config.routes.insert(name.clone(), route);
         println!(
             "{}",
-            format!("✅ Route added: {} → {}", prefix, target).green()
+            format!("✅ Route added: {} → {}", name, target).green()
         );
     } else {
         println!("{}", "❌ Operation cancelled.".red());
@@ -126,9 +183,32 @@ fn add_route(config: &mut ProxyConfig) -> Result<(), Box<dyn std::error::Error>>
 fn edit_route(config: &mut ProxyConfig) -> Result<(), Box<dyn std::error::Error>> {
     println!("{}", "\n=== Editing Route ===".yellow());
 
-    let routes: Vec<&String> = config.routes.keys().collect();
+    let routes: Vec<&String> = config
+        .routes
+        .iter()
+        .filter(|(_, route)| match &route.action {
+            // Routes with several or discovered upstreams have no single
+            // target to edit.
+            RouteAction::Proxy(proxy) => proxy.targets.is_empty() && proxy.srv.is_empty(),
+            RouteAction::Redirect(_) | RouteAction::Static(_) | RouteAction::Mock(_) => true,
+            RouteAction::DebugEcho | RouteAction::Metrics => false,
+        })
+        .map(|(name, _)| name)
+        .collect();
     if routes.is_empty() {
-        println!("{}", "⚠️ No routes found. Please add a route first.".red());
+        if config.routes.is_empty() {
+            println!("{}", "⚠️ No routes found. Please add a route first.".red());
+        } else {
+            println!(
+                "{}",
+                format!(
+                    "⚠️ None of the routes can be edited here. Routes with several targets, \
+                     SRV discovery, debug echo or metrics are edited in {}.",
+                    config::get_config_path().display()
+                )
+                .red()
+            );
+        }
         return Ok(());
     }
 
@@ -138,30 +218,103 @@ fn edit_route(config: &mut ProxyConfig) -> Result<(), Box<dyn std::error::Error>
         .interact()?;
 
     let selected_prefix = routes[selection].clone();
-    let current_target = &config.routes[&selected_prefix];
-
+    let current = &config.routes[&selected_prefix].action;
     println!(
         "{}",
-        format!("🔄 Current target: {}", current_target).cyan()
+        format!("🔄 Current target: {}", current.summary()).cyan()
     );
-    let new_target: String = Input::new()
-        .with_prompt("📝 Enter new target URL")
-        .with_initial_text(current_target)
-        .interact_text()?;
+
+    let action = match current {
+        RouteAction::Proxy(proxy) => {
+            let target: String = Input::new()
+                .with_prompt("📝 Enter new target URL")
+                .with_initial_text(&proxy.target)
+                .interact_text()?;
+            let mut proxy = proxy.clone();
+            proxy.target = target;
+            RouteAction::Proxy(proxy)
+        }
+        RouteAction::Redirect(redirect) => {
+            let location: String = Input::new()
+                .with_prompt("🔀 Enter redirect location")
+                .with_initial_text(&redirect.location)
+                .interact_text()?;
+            let current_status = RedirectRoute::STATUSES
+                .iter()
+                .position(|status| *status == redirect.status)
+                .unwrap_or(1);
+            let status = Select::with_theme(&ColorfulTheme::default())
+                .with_prompt("🔢 Select redirect status")
+                .items(&RedirectRoute::STATUSES)
+                .default(current_status)
+                .interact()?;
+            RouteAction::Redirect(RedirectRoute {
+                location,
+                status: RedirectRoute::STATUSES[status],
+            })
+        }
+        RouteAction::Static(files) => {
+            let root: String = Input::new()
+                .with_prompt("📁 Enter directory to serve")
+                .with_initial_text(&files.root)
+                .interact_text()?;
+            let spa = Confirm::new()
+                .with_prompt("🧭 Serve index.html for unknown paths (single-page app)?")
+                .default(files.spa)
+                .interact()?;
+            RouteAction::Static(StaticRoute { root, spa })
+        }
+        RouteAction::Mock(mock) => {
+            let mut mock = mock.clone();
+            mock.status = Input::new()
+                .with_prompt("🔢 Enter response status")
+                .default(mock.status)
+                .interact_text()?;
+
+            let content_type = mock
+                .headers
+                .keys()
+                .find(|name| name.eq_ignore_ascii_case("content-type"))
+                .cloned()
+                .unwrap_or_else(|| "content-type".to_string());
+            let current_type = mock.headers.remove(&content_type).unwrap_or_default();
+            let new_type: String = Input::new()
+                .with_prompt("🏷️ Enter content type (optional, e.g., application/json)")
+                .with_initial_text(&current_type)
+                .allow_empty(true)
+                .interact_text()?;
+            if !new_type.is_empty() {
+                mock.headers.insert(content_type, new_type);
+            }
+
+            // Bodies read from a file are changed by editing the file.
+            if mock.body_file.is_none() {
+                let body: String = Input::new()
+                    .with_prompt("📝 Enter response body (optional)")
+                    .with_initial_text(mock.body.as_deref().unwrap_or_default())
+                    .allow_empty(true)
+                    .interact_text()?;
+                mock.body = (!body.is_empty()).then_some(body);
+            }
+            RouteAction::Mock(mock)
+        }
+        RouteAction::DebugEcho | RouteAction::Metrics => unreachable!("not offered for editing"),
+    };
+    let summary = action.summary();
 
     if Confirm::new()
         .with_prompt(format!(
             "🔄 Update route {} → {}?",
-            selected_prefix, new_target
+            selected_prefix, summary
         ))
         .interact()?
     {
-        config
-            .routes
-            .insert(selected_prefix.clone(), new_target.clone());
+        if let Some(route) = config.routes.get_mut(&selected_prefix) {
+            route.action = action;
+        }
         println!(
             "{}",
-            format!("✅ Route updated: {} → {}", selected_prefix, new_target).green()
+            format!("✅ Route updated: {} → {}", selected_prefix, summary).green()
         );
     } else {
         println!("{}", "❌ Operation cancelled.".red());
@@ -200,44 +353,86 @@ fn delete_route(config: &mut ProxyConfig) -> Result<(), Box<dyn std::error::Erro
     Ok(())
 }
 
-fn list_routes(config: &ProxyConfig) {
-    if config.routes.is_empty() {
+fn list_routes(router: &Router) {
+    if router.routes().is_empty() {
         println!("{}", "⚠️ No routes configured.".red());
     } else {
         println!("{}", "\n🔗 Configured Routes:".yellow());
-        for (prefix, target) in &config.routes {
-            println!("{}", format!("✅ {} → {}", prefix, target).green());
+        for entry in router.routes() {
+            let mut label = entry.label();
+            if !entry.route.predicates.is_empty() {
+                let predicates: Vec<String> = entry
+                    .route
+                    .predicates
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect();
+                label.push_str(&format!(" [{}]", predicates.join(", ")));
+            }
+            println!(
+                "{}",
+                format!("✅ {} → {}", label, entry.route.action.summary()).green()
+            );
+            if let (Some(prefix), RouteAction::Proxy(proxy)) = (entry.prefix(), &entry.route.action)
+            {
+                println!("   {}", rewrite::describe(proxy, prefix).dimmed());
+            }
+            if let Some(balancer) = &entry.balancer {
+                list_upstreams(balancer);
+            }
+            if let RouteAction::Proxy(proxy) = &entry.route.action {
+                if let Some(sticky) = &proxy.sticky {
+                    println!("   {}", sticky.summary().dimmed());
+                }
+                if let Some(retry) = &proxy.retry {
+                    println!("   {}", retry.summary().dimmed());
+                }
+                println!("   {}", format!("📡 {}", proxy.protocol).dimmed());
+                if let Some(tls) = &proxy.tls {
+                    println!("   {}", tls.summary().dimmed());
+                }
+            }
+            if let Some(client) = &entry.client {
+                if let Some(upstream_proxy) = client.upstream_proxy() {
+                    println!("   {}", upstream_proxy.summary().dimmed());
+                }
+                println!("   {}", client.timeouts().summary().dimmed());
+            }
         }
     }
+
+    let fallback = match router.fallback() {
+        Fallback::NotFound => "404 Not Found".to_string(),
+        Fallback::Proxy(proxy) => proxy.summary(),
+        Fallback::Redirect(redirect) => redirect.summary(),
+    };
+    println!("{}", format!("↪️ Unmatched requests → {}", fallback).cyan());
 }
 
-async fn handle_request(
-    client_ip: IpAddr,
-    mut req: Request<Body>,
-    config: Arc<ProxyConfig>,
-) -> Result<Response<Body>, Infallible> {
-    let path = req.uri().path();
-
-    for (prefix, target) in &config.routes {
-        if path.starts_with(prefix) {
-            let new_path = &path[prefix.len()..];
-            let new_uri = format!("{}{}", target, new_path);
-            *req.uri_mut() = new_uri.parse().unwrap();
-
-            match hyper_reverse_proxy::call(client_ip, target, req).await {
-                Ok(response) => return Ok(response),
-                Err(_error) => {
-                    return Ok(Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(Body::empty())
-                        .unwrap())
-                }
-            }
+fn list_upstreams(balancer: &Balancer) {
+    let check = balancer.health_check();
+    let outlier = balancer.outlier_detection();
+    let upstreams = balancer.upstreams();
+    if upstreams.len() < 2 && check.is_none() && outlier.is_none() && balancer.srv().is_none() {
+        return;
+    }
+    if upstreams.len() > 1 {
+        println!("   {}", format!("⚖️ {}", balancer.strategy()).dimmed());
+    }
+    if let Some(check) = check {
+        println!("   {}", check.summary().dimmed());
+    }
+    if let Some(outlier) = outlier {
+        println!("   {}", outlier.summary().dimmed());
+    }
+    for upstream in upstreams {
+        let line = format!("• {} (weight {})", upstream.url, upstream.weight);
+        match (check, upstream.is_healthy()) {
+            (None, _) => println!("   {}", line.dimmed()),
+            (Some(_), true) => println!("   {}", format!("{} ✅ healthy", line).green()),
+            (Some(_), false) => println!("   {}", format!("{} ❌ unhealthy", line).red()),
         }
     }
-
-    let body_str = format!("{:?}", req);
-    Ok(Response::new(Body::from(body_str)))
 }
 
 #[tokio::main]
@@ -245,19 +440,28 @@ async fn main() {
     let cli = Cli::parse();
     match cli.command {
         Commands::Start(args) => {
-            let config = Arc::new(load_config());
+            let router = match Router::new(&load_config()) {
+                Ok(router) => Arc::new(router),
+                Err(e) => {
+                    eprintln!("{}", format!("❌ Invalid route: {}", e).red());
+                    std::process::exit(1);
+                }
+            };
             let bind_addr = format!("127.0.0.1:{}", args.port);
             let addr: SocketAddr = bind_addr.parse().expect("Could not parse ip:port.");
-            list_routes(&config);
+            dns::start(&router).await;
+            health::check_all(&router).await;
+            list_routes(&router);
+            health::spawn(&router);
 
             let make_svc = make_service_fn(move |conn: &AddrStream| {
                 let remote_addr = conn.remote_addr().ip();
-                let config = Arc::clone(&config);
+                let router = Arc::clone(&router);
 
                 async move {
                     Ok::<_, Infallible>(service_fn(move |req| {
-                        let config = Arc::clone(&config);
-                        handle_request(remote_addr, req, config)
+                        let router = Arc::clone(&router);
+                        handle_request(remote_addr, req, router)
                     }))
                 }
             });
@@ -0,0 +1,67 @@
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Response};
+use std::fmt::Write;
+
+use crate::pool::PoolStats;
+use crate::router::Router;
+
+/// A gauge's name, help text, and how to read it from a pool's stats.
+type Gauge = (&'static str, &'static str, fn(&PoolStats) -> usize);
+
+/// The gauges exported per upstream host.
+const GAUGES: [Gauge; 4] = [
+    (
+        "prin_upstream_connections_open",
+        "Connections open to an upstream.",
+        |stats| stats.open,
+    ),
+    (
+        "prin_upstream_connections_idle",
+        "Open connections to an upstream waiting to be reused.",
+        |stats| stats.idle,
+    ),
+    (
+        "prin_upstream_connections_in_use",
+        "Open connections to an upstream carrying a request.",
+        |stats| stats.in_use,
+    ),
+    (
+        "prin_upstream_requests_in_flight",
+        "Requests sent to an upstream whose response is not done yet.",
+        |stats| stats.in_flight,
+    ),
+];
+
+/// Answers a `metrics` route with the connection pool gauges of every
+/// upstream host, in the Prometheus text format.
+pub fn respond(router: &Router) -> Response<Body> {
+    let pools = router.pool_stats();
+
+    let mut body = String::new();
+    for (name, help, value) in GAUGES {
+        let _ = writeln!(body, "# HELP {} {}", name, help);
+        let _ = writeln!(body, "# TYPE {} gauge", name);
+        for stats in &pools {
+            let _ = writeln!(
+                body,
+                "{}{{upstream=\"{}\"}} {}",
+                name,
+                escape(&stats.upstream),
+                value(stats)
+            );
+        }
+    }
+
+    Response::builder()
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Escapes a label value for the text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
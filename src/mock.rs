@@ -0,0 +1,89 @@
+use colored::*;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use std::collections::HashMap;
+
+use crate::config::MockRoute;
+use crate::error_page::{self, Format};
+use crate::pattern::{self, Captures};
+
+/// Answers with the route's configured status, headers and body. With
+/// `template` on, `{path}`, `{query}` and captured groups are substituted
+/// into header values and the body. If the response can't be built, the
+/// client gets the `500` error page, naming the route `label`.
+pub async fn respond(
+    mock: &MockRoute,
+    req: &Request<Body>,
+    path: &str,
+    captures: Captures,
+    label: &str,
+    pages: &HashMap<u16, String>,
+) -> Response<Body> {
+    let server_error = |reason: String| async move {
+        eprintln!("{}", format!("❌ Mock route {}: {}", label, reason).red());
+        let status = StatusCode::INTERNAL_SERVER_ERROR;
+        error_page::respond(status, Some(label), &reason, Format::of(req), pages).await
+    };
+    let query = req
+        .uri()
+        .query()
+        .map(|query| format!("?{}", query))
+        .unwrap_or_default();
+    let captures = captures.with("path", path).with("query", &query);
+    let render = |text: &str| match mock.template {
+        true => pattern::expand(text, &captures),
+        false => text.to_string(),
+    };
+
+    let body = match (&mock.body, &mock.body_file) {
+        (_, Some(file)) => match tokio::fs::read(file).await {
+            Ok(bytes) if mock.template => render(&String::from_utf8_lossy(&bytes)).into_bytes(),
+            Ok(bytes) => bytes,
+            Err(error) => return server_error(format!("body file {}: {}", file, error)).await,
+        },
+        (Some(body), None) => render(body).into_bytes(),
+        (None, None) => Vec::new(),
+    };
+
+    // The status and header names are checked when the routing table is built.
+    let status = StatusCode::from_u16(mock.status).unwrap_or(StatusCode::OK);
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+
+    let headers = response.headers_mut();
+    if let Some(file) = &mock.body_file {
+        let content_type = mime_guess::from_path(file).first_or_octet_stream();
+        if let Ok(value) = HeaderValue::from_str(content_type.as_ref()) {
+            headers.insert(CONTENT_TYPE, value);
+        }
+    }
+    for (name, value) in &mock.headers {
+        let value = render(value);
+        match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                headers.insert(name, value);
+            }
+            _ => return server_error(format!("invalid header {}: {}", name, value)).await,
+        }
+    }
+
+    response
+}
+
+/// Checks what can be checked before the first request arrives.
+pub fn check(mock: &MockRoute) -> Result<(), String> {
+    if StatusCode::from_u16(mock.status).is_err() {
+        return Err(format!("invalid mock status {}", mock.status));
+    }
+    if mock.body.is_some() && mock.body_file.is_some() {
+        return Err("mock routes take either body or body_file, not both".to_string());
+    }
+    for name in mock.headers.keys() {
+        HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| format!("invalid mock header {}: {}", name, e))?;
+    }
+    Ok(())
+}
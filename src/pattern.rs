@@ -0,0 +1,160 @@
+use regex::Regex;
+use std::collections::HashMap;
+
+/// Groups captured by a pattern route. Owned, so they can be used after the
+/// request they were taken from is modified.
+#[derive(Default)]
+pub struct Captures {
+    groups: Vec<Option<String>>,
+    names: HashMap<String, usize>,
+}
+
+impl Captures {
+    pub fn new(regex: &Regex, caps: &regex::Captures) -> Self {
+        let groups = caps
+            .iter()
+            .map(|group| group.map(|m| m.as_str().to_string()))
+            .collect();
+        let names = regex
+            .capture_names()
+            .enumerate()
+            .filter_map(|(index, name)| Some((name?.to_string(), index)))
+            .collect();
+
+        Captures { groups, names }
+    }
+
+    /// Adds a named value, unless a captured group already uses the name.
+    pub fn with(mut self, name: &str, value: &str) -> Self {
+        if !self.names.contains_key(name) {
+            self.names.insert(name.to_string(), self.groups.len());
+            self.groups.push(Some(value.to_string()));
+        }
+        self
+    }
+
+    /// The text of a group, empty if the group exists but did not take part
+    /// in the match. `None` only for groups the pattern does not have.
+    fn get(&self, name: &str) -> Option<&str> {
+        let index = match name.parse::<usize>() {
+            Ok(index) => index,
+            Err(_) => *self.names.get(name)?,
+        };
+        let group = self.groups.get(index)?;
+        Some(group.as_deref().unwrap_or_default())
+    }
+}
+
+/// Compiles a parameterized path such as `/users/{id}/avatar` into an
+/// anchored regex where every `{name}` captures a single path segment.
+pub fn compile_path_template(path: &str) -> Result<Regex, regex::Error> {
+    let mut pattern = String::from("^");
+    let mut rest = path.trim_end_matches('/');
+
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        pattern.push_str(&regex::escape(&rest[..start]));
+        let name = &rest[start + 1..start + len];
+        pattern.push_str(&format!("(?P<{}>[^/]+)", name));
+        rest = &rest[start + len + 1..];
+    }
+    pattern.push_str(&regex::escape(rest));
+    pattern.push_str("/?$");
+
+    Regex::new(&pattern)
+}
+
+/// Whether `path` contains `{name}` placeholders.
+pub fn is_path_template(path: &str) -> bool {
+    path.contains('{') && path.contains('}')
+}
+
+/// Substitutes captured groups into `template`.
+///
+/// Groups can be referenced as `{name}`, `$1`, `$name`, `${1}` or `${name}`;
+/// `$$` is a literal `$`. Captured text is inserted as-is, so it keeps the
+/// percent-encoding it had in the request path. References to groups the
+/// pattern does not have are left untouched, so `$5` in a mock body stays
+/// `$5` unless there is a fifth group.
+pub fn expand(template: &str, caps: &Captures) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find(['$', '{']) {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(after) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            match after.split_once('}') {
+                Some((name, after)) => match caps.get(name) {
+                    Some(value) => {
+                        out.push_str(value);
+                        rest = after;
+                    }
+                    None => {
+                        out.push_str("${");
+                        rest = &rest[2..];
+                    }
+                },
+                None => {
+                    out.push('$');
+                    rest = &rest[1..];
+                }
+            }
+        } else if let Some(after) = rest.strip_prefix('$') {
+            let len = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            match caps.get(&after[..len]).filter(|_| len > 0) {
+                Some(value) => out.push_str(value),
+                None => out.push_str(&rest[..len + 1]),
+            }
+            rest = &after[len..];
+        } else {
+            let after = &rest[1..];
+            let group = after
+                .split_once('}')
+                .and_then(|(name, after)| Some((caps.get(name)?, after)));
+            match group {
+                Some((value, after)) => {
+                    out.push_str(value);
+                    rest = after;
+                }
+                _ => {
+                    out.push('{');
+                    rest = after;
+                }
+            }
+        }
+    }
+    out.push_str(rest);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captures(pattern: &str, path: &str) -> Captures {
+        let regex = Regex::new(pattern).unwrap();
+        let caps = regex.captures(path).unwrap();
+        Captures::new(&regex, &caps)
+    }
+
+    #[test]
+    fn unknown_references_stay_literal() {
+        let caps = captures(r"^/items/(?P<id>\d+)(/x)?$", "/items/7").with("path", "/items/7");
+        assert_eq!(expand(r#"{"price": "$5"}"#, &caps), r#"{"price": "$5"}"#);
+        assert_eq!(expand("${x} $name {nope}", &caps), "${x} $name {nope}");
+        assert_eq!(expand("$1 ${id} {id} $id", &caps), "7 7 7 7");
+        assert_eq!(expand("[$2]{path}", &caps), "[]/items/7");
+        assert_eq!(expand("$$1 costs $ 5$", &caps), "$1 costs $ 5$");
+        assert_eq!(expand("${id", &caps), "${id");
+    }
+}
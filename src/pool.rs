@@ -0,0 +1,139 @@
+use hyper::Uri;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::connector;
+
+/// Connection counts for the upstream hosts of one client, for metrics and
+/// for `max_connections_per_host`.
+pub struct Pools {
+    max_connections_per_host: Option<usize>,
+    hosts: Mutex<HashMap<String, Arc<HostPool>>>,
+}
+
+/// One upstream host's connections.
+pub struct HostPool {
+    open: AtomicUsize,
+    in_flight: AtomicUsize,
+    /// Present when connections per host are capped.
+    limit: Option<Arc<Semaphore>>,
+}
+
+/// A snapshot of one host's connections.
+pub struct PoolStats {
+    pub upstream: String,
+    pub open: usize,
+    pub idle: usize,
+    pub in_use: usize,
+    pub in_flight: usize,
+}
+
+impl Pools {
+    pub fn new(max_connections_per_host: Option<usize>) -> Self {
+        Pools {
+            max_connections_per_host,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The pool of the host `uri` points at.
+    pub fn host(&self, uri: &Uri) -> Arc<HostPool> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let pool = hosts.entry(key(uri)).or_insert_with(|| {
+            Arc::new(HostPool {
+                open: AtomicUsize::new(0),
+                in_flight: AtomicUsize::new(0),
+                limit: self
+                    .max_connections_per_host
+                    .map(|max| Arc::new(Semaphore::new(max.max(1)))),
+            })
+        });
+        Arc::clone(pool)
+    }
+
+    /// Current counts for every host connected to so far, sorted by host.
+    pub fn stats(&self) -> Vec<PoolStats> {
+        let hosts = self.hosts.lock().unwrap();
+        let mut stats: Vec<PoolStats> = hosts
+            .iter()
+            .map(|(upstream, pool)| {
+                let open = pool.open.load(Ordering::Relaxed);
+                let in_flight = pool.in_flight.load(Ordering::Relaxed);
+                // Over HTTP/1.1 each request in flight holds a connection of
+                // its own; over HTTP/2 they share, so this can't exceed
+                // `open`.
+                let in_use = in_flight.min(open);
+                PoolStats {
+                    upstream: upstream.clone(),
+                    open,
+                    idle: open - in_use,
+                    in_use,
+                    in_flight,
+                }
+            })
+            .collect();
+        stats.sort_by(|a, b| a.upstream.cmp(&b.upstream));
+        stats
+    }
+}
+
+impl HostPool {
+    /// Reserves room for a new connection, waiting while the host is at
+    /// `max_connections_per_host`. The connection counts as open until the
+    /// returned slot is dropped.
+    pub async fn open(self: Arc<Self>) -> Slot {
+        let permit = match &self.limit {
+            Some(limit) => Arc::clone(limit).acquire_owned().await.ok(),
+            None => None,
+        };
+        self.open.fetch_add(1, Ordering::Relaxed);
+        Slot {
+            pool: self,
+            _permit: permit,
+        }
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped.
+    pub fn start_request(self: Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight { pool: self }
+    }
+}
+
+/// An open connection's place in its host's pool.
+pub struct Slot {
+    pool: Arc<HostPool>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.pool.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A request in flight, from sending it until its response body is done.
+pub struct InFlight {
+    pool: Arc<HostPool>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.pool.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How a host shows up in metrics: the origin of its URI, or the socket
+/// path for Unix sockets.
+fn key(uri: &Uri) -> String {
+    if let Some(path) = connector::socket_path(uri) {
+        return format!("unix:{}", path.display());
+    }
+    format!(
+        "{}://{}",
+        uri.scheme_str().unwrap_or("http"),
+        uri.authority().map(|a| a.as_str()).unwrap_or_default()
+    )
+}
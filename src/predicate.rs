@@ -0,0 +1,153 @@
+use hyper::header::COOKIE;
+use hyper::{Body, Request};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::rewrite::percent_decode;
+
+/// An extra condition a request has to meet, checked after the route's host
+/// and path matched. A `value` of `None` only requires the header, cookie or
+/// query parameter to be present.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged, try_from = "RawPredicate")]
+pub enum Predicate {
+    Method {
+        method: String,
+    },
+    Header {
+        header: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<String>,
+    },
+    Cookie {
+        cookie: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<String>,
+    },
+    Query {
+        query: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<String>,
+    },
+}
+
+/// The fields any predicate may use. Deserializing through this rejects
+/// misspelled keys and predicates that mix kinds, which `untagged` alone
+/// would accept by matching the first variant and dropping the rest.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPredicate {
+    method: Option<String>,
+    header: Option<String>,
+    cookie: Option<String>,
+    query: Option<String>,
+    value: Option<String>,
+}
+
+impl TryFrom<RawPredicate> for Predicate {
+    type Error = String;
+
+    fn try_from(raw: RawPredicate) -> Result<Self, String> {
+        let value = raw.value;
+        match (raw.method, raw.header, raw.cookie, raw.query) {
+            (Some(_), None, None, None) if value.is_some() => {
+                Err("method predicates take no value".into())
+            }
+            (Some(method), None, None, None) => Ok(Predicate::Method { method }),
+            (None, Some(header), None, None) => Ok(Predicate::Header { header, value }),
+            (None, None, Some(cookie), None) => Ok(Predicate::Cookie { cookie, value }),
+            (None, None, None, Some(query)) => Ok(Predicate::Query { query, value }),
+            _ => Err("a predicate needs exactly one of method, header, cookie or query".into()),
+        }
+    }
+}
+
+impl Predicate {
+    pub fn matches(&self, req: &Request<Body>) -> bool {
+        match self {
+            Predicate::Method { method } => req.method().as_str().eq_ignore_ascii_case(method),
+            Predicate::Header { header, value } => req
+                .headers()
+                .get_all(header.as_str())
+                .iter()
+                .any(|actual| value_matches(value, actual.to_str().ok())),
+            Predicate::Cookie { cookie, value } => cookies(req)
+                .any(|(name, actual)| name == cookie && value_matches(value, Some(actual))),
+            Predicate::Query { query, value } => {
+                let pairs = req.uri().query().unwrap_or_default().split('&');
+                pairs.filter(|pair| !pair.is_empty()).any(|pair| {
+                    let (name, actual) = pair.split_once('=').unwrap_or((pair, ""));
+                    percent_decode(name, true).as_deref() == Some(query.as_str())
+                        && value_matches(value, percent_decode(actual, true).as_deref())
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (kind, name, value) = match self {
+            Predicate::Method { method } => return write!(f, "{}", method.to_uppercase()),
+            Predicate::Header { header, value } => ("header", header, value),
+            Predicate::Cookie { cookie, value } => ("cookie", cookie, value),
+            Predicate::Query { query, value } => ("query", query, value),
+        };
+        match value {
+            Some(value) => write!(f, "{} {}={}", kind, name, value),
+            None => write!(f, "{} {}", kind, name),
+        }
+    }
+}
+
+fn value_matches(expected: &Option<String>, actual: Option<&str>) -> bool {
+    match (expected, actual) {
+        (None, _) => true,
+        (Some(expected), Some(actual)) => expected == actual,
+        (Some(_), None) => false,
+    }
+}
+
+/// `(name, value)` pairs from every `Cookie` header of the request.
+pub fn cookies(req: &Request<Body>) -> impl Iterator<Item = (&str, &str)> {
+    req.headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .filter_map(|cookie| {
+            let (name, value) = cookie.trim().split_once('=')?;
+            Some((name.trim(), value.trim().trim_matches('"')))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: serde_json::Value) -> Result<Predicate, String> {
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn predicates_reject_mixed_and_unknown_keys() {
+        let ok = parse(serde_json::json!({ "header": "x-env", "value": "qa" })).unwrap();
+        assert_eq!(ok.to_string(), "header x-env=qa");
+        assert_eq!(
+            parse(serde_json::json!({ "method": "get" }))
+                .unwrap()
+                .to_string(),
+            "GET"
+        );
+
+        for bad in [
+            serde_json::json!({ "method": "GET", "header": "x" }),
+            serde_json::json!({ "method": "GET", "value": "x" }),
+            serde_json::json!({ "headr": "x-env" }),
+            serde_json::json!({ "cookie": "a", "vlaue": "b" }),
+            serde_json::json!({}),
+        ] {
+            assert!(parse(bad.clone()).is_err(), "{} should be rejected", bad);
+        }
+    }
+}
@@ -0,0 +1,418 @@
+use colored::*;
+use hyper::header::{CONTENT_TYPE, LOCATION, RETRY_AFTER, SET_COOKIE};
+use hyper::http::request::Parts;
+use hyper::{Body, Request, Response, StatusCode};
+use std::convert::Infallible;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::balancer::{Balancer, Pick};
+use crate::config::{Fallback, ProxyRoute, RedirectRoute, RouteAction};
+use crate::error_page::{self, Format};
+use crate::metrics;
+use crate::mock;
+use crate::pattern::{self, Captures};
+use crate::retry::{self, Budget, Buffered};
+use crate::rewrite::{self, RewriteError, Target};
+use crate::router::Router;
+use crate::static_files;
+use crate::upstream::{UpstreamClient, UpstreamError};
+
+pub async fn handle_request(
+    client_ip: IpAddr,
+    req: Request<Body>,
+    router: Arc<Router>,
+) -> Result<Response<Body>, Infallible> {
+    if let Some(route_match) = router.find(&req) {
+        let entry = route_match.entry;
+        let response = match &entry.route.action {
+            RouteAction::Proxy(proxy) => {
+                let label = entry.label();
+                let destination = Destination {
+                    label: &label,
+                    proxy,
+                    balancer: entry
+                        .balancer
+                        .as_ref()
+                        .expect("proxy routes have a balancer"),
+                    client: entry.client.as_ref().expect("proxy routes have a client"),
+                    budget: router.retry_budget(),
+                };
+                let format = Format::of(&req);
+                let rest = route_match.rest;
+                match forward(client_ip, req, destination, rest, &route_match.captures).await {
+                    Ok(response) => response,
+                    Err(error) => gateway_error(&label, error, format, &router).await,
+                }
+            }
+            RouteAction::Redirect(redirect) => {
+                let format = Format::of(&req);
+                let path = remaining_path(route_match.rest.as_deref(), &req);
+                match redirect_to(redirect, &req, path, route_match.captures) {
+                    Ok(response) => response,
+                    Err(error) => gateway_error(&entry.label(), error, format, &router).await,
+                }
+            }
+            RouteAction::Static(files) => {
+                let path = remaining_path(route_match.rest.as_deref(), &req);
+                let label = entry.label();
+                static_files::serve(files, &req, path, &label, router.error_pages()).await
+            }
+            RouteAction::Mock(mock) => {
+                let path = remaining_path(route_match.rest.as_deref(), &req);
+                let label = entry.label();
+                let captures = route_match.captures;
+                mock::respond(mock, &req, path, captures, &label, router.error_pages()).await
+            }
+            RouteAction::DebugEcho => debug_echo(client_ip, &req),
+            RouteAction::Metrics => metrics::respond(&router),
+        };
+        return Ok(response);
+    }
+
+    let format = Format::of(&req);
+    let response = match router.fallback() {
+        Fallback::NotFound => {
+            let reason = format!("No route matches {}.", req.uri().path());
+            error_page::respond(
+                StatusCode::NOT_FOUND,
+                None,
+                &reason,
+                format,
+                router.error_pages(),
+            )
+            .await
+        }
+        Fallback::Proxy(proxy) => {
+            let destination = Destination {
+                label: "fallback",
+                proxy,
+                balancer: router
+                    .fallback_balancer()
+                    .expect("proxy fallback has a balancer"),
+                client: router
+                    .fallback_client()
+                    .expect("proxy fallback has a client"),
+                budget: router.retry_budget(),
+            };
+            let path = req.uri().path().to_string();
+            let captures = Captures::default();
+            match forward(client_ip, req, destination, Some(path), &captures).await {
+                Ok(response) => response,
+                Err(error) => gateway_error("fallback", error, format, &router).await,
+            }
+        }
+        Fallback::Redirect(redirect) => {
+            match redirect_to(redirect, &req, req.uri().path(), Captures::default()) {
+                Ok(response) => response,
+                Err(error) => gateway_error("fallback", error, format, &router).await,
+            }
+        }
+    };
+    Ok(response)
+}
+
+/// The path below a matched prefix as the client sent it. An exact hit on
+/// the prefix leaves nothing, so `/old` redirects to `/new` rather than
+/// `/new/`. Pattern routes use the whole path.
+fn remaining_path<'a>(rest: Option<&'a str>, req: &'a Request<Body>) -> &'a str {
+    match rest {
+        Some("/") if !req.uri().path().ends_with('/') => "",
+        Some(rest) => rest,
+        None => req.uri().path(),
+    }
+}
+
+/// Why a request could not be answered with an upstream's response.
+enum GatewayError {
+    /// Every upstream of the route is out of rotation.
+    NoUpstream { retry_after: Option<Duration> },
+    /// The request could not be rewritten for its upstream.
+    Rewrite(RewriteError),
+    /// The upstream failed to answer.
+    Upstream(UpstreamError),
+    /// The client's request body could not be read for buffering.
+    RequestBody(hyper::Error),
+}
+
+impl GatewayError {
+    fn status(&self) -> StatusCode {
+        match self {
+            GatewayError::NoUpstream { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            GatewayError::Upstream(UpstreamError::Timeout(_)) => StatusCode::GATEWAY_TIMEOUT,
+            GatewayError::Rewrite(_) | GatewayError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            GatewayError::RequestBody(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for GatewayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GatewayError::NoUpstream { .. } => write!(f, "no upstream available"),
+            GatewayError::Rewrite(error) => write!(f, "{}", error),
+            GatewayError::Upstream(error) => write!(f, "{}", error),
+            GatewayError::RequestBody(error) => {
+                write!(f, "could not read request body: {}", error)
+            }
+        }
+    }
+}
+
+/// Logs `error` and answers with the matching error page. A `503` tells
+/// the client when to come back rather than letting it hammer a broken
+/// service.
+async fn gateway_error(
+    route: &str,
+    error: GatewayError,
+    format: Format,
+    router: &Router,
+) -> Response<Body> {
+    eprintln!("{}", format!("❌ Route {}: {}", route, error).red());
+    let reason = error.to_string();
+    let mut response = error_page::respond(
+        error.status(),
+        Some(route),
+        &reason,
+        format,
+        router.error_pages(),
+    )
+    .await;
+
+    if let GatewayError::NoUpstream {
+        retry_after: Some(retry_after),
+    } = error
+    {
+        // Whole seconds, rounded up so clients don't come back too early.
+        let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, secs.max(1).into());
+    }
+    response
+}
+
+/// A proxy route, or the proxy fallback, as `forward` sees it.
+struct Destination<'a> {
+    label: &'a str,
+    proxy: &'a ProxyRoute,
+    balancer: &'a Balancer,
+    client: &'a UpstreamClient,
+    budget: &'a Budget,
+}
+
+/// Forwards `req` to an upstream picked by the route's balancer. `rest` is
+/// the path left after a prefix route's prefix; pattern routes pass `None`
+/// and their `captures`.
+///
+/// With a retry policy, eligible requests have their body buffered so that
+/// failed attempts can be repeated, each on a freshly picked upstream.
+async fn forward(
+    client_ip: IpAddr,
+    req: Request<Body>,
+    destination: Destination<'_>,
+    rest: Option<String>,
+    captures: &Captures,
+) -> Result<Response<Body>, GatewayError> {
+    let Destination {
+        label,
+        proxy,
+        client,
+        budget,
+        ..
+    } = destination;
+    budget.record_request();
+    let deadline = client.deadline();
+
+    let policy = proxy
+        .retry
+        .as_ref()
+        .filter(|policy| retry::is_eligible(policy, &req));
+    let (mut original, buffered) = match policy {
+        Some(policy) => {
+            let (parts, body) = req.into_parts();
+            match retry::buffer(body, policy.max_body_bytes).await {
+                Ok(Buffered::Complete(body)) => (None, Some((parts, body))),
+                Ok(Buffered::TooLarge(body)) => (Some(Request::from_parts(parts, body)), None),
+                Err(error) => return Err(GatewayError::RequestBody(error)),
+            }
+        }
+        None => (Some(req), None),
+    };
+    // Only a buffered body can be sent again.
+    let attempts = match (policy, &buffered) {
+        (Some(policy), Some(_)) => policy.attempts.max(1),
+        _ => 1,
+    };
+
+    let mut attempt = 1;
+    loop {
+        let req = match &buffered {
+            Some((parts, body)) => rebuild(parts, Body::from(body.clone())),
+            None => original.take().expect("unbuffered requests are sent once"),
+        };
+        let rest = rest.as_deref();
+        let (mut pick, result) =
+            send(client_ip, req, &destination, rest, captures, deadline).await?;
+        let set_cookie = pick.set_cookie.take();
+
+        let failure = retry::failure(&result);
+        match (&failure, &result) {
+            (Some(_), _) => pick.failed(),
+            (None, Ok(_)) => pick.succeeded(),
+            (None, Err(_)) => drop(pick),
+        }
+
+        if let (Some(policy), Some(failure)) = (policy, failure) {
+            let expired = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            if attempt < attempts
+                && !expired
+                && policy.retry_on.contains(&failure)
+                && budget.try_retry()
+            {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "🔁 Route {}: retrying after {} (attempt {}/{})",
+                        label,
+                        failure,
+                        attempt + 1,
+                        attempts
+                    )
+                    .yellow()
+                );
+                tokio::time::sleep(retry::backoff(policy, attempt)).await;
+                attempt += 1;
+                continue;
+            }
+        }
+
+        let mut response = result.map_err(GatewayError::Upstream)?;
+        if let Some(cookie) = set_cookie {
+            response.headers_mut().append(SET_COOKIE, cookie);
+        }
+        return Ok(response);
+    }
+}
+
+/// One attempt at forwarding `req`: picks an upstream, rewrites the request
+/// for it and sends it. The outer `Err` is for requests that could not be
+/// sent at all, the inner result is the upstream's answer.
+async fn send<'a>(
+    client_ip: IpAddr,
+    mut req: Request<Body>,
+    destination: &Destination<'a>,
+    rest: Option<&str>,
+    captures: &Captures,
+    deadline: Option<Instant>,
+) -> Result<(Pick, Result<Response<Body>, UpstreamError>), GatewayError> {
+    let Destination {
+        proxy,
+        balancer,
+        client,
+        ..
+    } = *destination;
+    let mut pick = match balancer.pick(client_ip, &req) {
+        Some(pick) => pick,
+        None => {
+            return Err(GatewayError::NoUpstream {
+                retry_after: balancer.retry_after(),
+            })
+        }
+    };
+    let target = &pick.upstream.url;
+
+    let query = req.uri().query();
+    let new_uri = match rest {
+        Some(rest) => {
+            let stripped = remaining_path(Some(rest), &req);
+            let path = rewrite::rewrite_path(proxy, req.uri().path(), stripped);
+            Target::parse(target).and_then(|target| target.uri(&path, query))
+        }
+        None => rewrite::template_uri(&pattern::expand(target, captures), req.uri().path(), query),
+    };
+    let new_uri = new_uri.map_err(GatewayError::Rewrite)?;
+
+    let headers: Vec<(String, String)> = proxy
+        .request_headers
+        .iter()
+        .map(|(name, value)| match rest {
+            Some(_) => (name.clone(), value.clone()),
+            None => (name.clone(), pattern::expand(value, captures)),
+        })
+        .collect();
+    rewrite::set_headers(req.headers_mut(), &headers).map_err(GatewayError::Rewrite)?;
+    *req.uri_mut() = new_uri;
+
+    // Long downloads and streams keep counting against the upstream.
+    let outstanding = pick.hold();
+    let result = client.send(client_ip, req, deadline, outstanding).await;
+    Ok((pick, result))
+}
+
+/// A copy of a buffered request for another attempt.
+fn rebuild(parts: &Parts, body: Body) -> Request<Body> {
+    let mut req = Request::new(body);
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+    req
+}
+
+/// Answers with a redirect to the route's `location` template.
+fn redirect_to(
+    redirect: &RedirectRoute,
+    req: &Request<Body>,
+    path: &str,
+    captures: Captures,
+) -> Result<Response<Body>, GatewayError> {
+    let query = req
+        .uri()
+        .query()
+        .map(|query| format!("?{}", query))
+        .unwrap_or_default();
+    let captures = captures.with("path", path).with("query", &query);
+    let location = pattern::expand(&redirect.location, &captures);
+
+    // The status is checked when the routing table is built.
+    let status = StatusCode::from_u16(redirect.status).unwrap_or(StatusCode::FOUND);
+    Response::builder()
+        .status(status)
+        .header(LOCATION, location.as_str())
+        .body(Body::empty())
+        .map_err(|error| {
+            let reason = format!("{}: {} ({})", LOCATION, location, error);
+            GatewayError::Rewrite(RewriteError::Header(reason))
+        })
+}
+
+/// Describes the request as JSON. Only reachable through a `debug_echo`
+/// route, since it reflects every header back, cookies included.
+fn debug_echo(client_ip: IpAddr, req: &Request<Body>) -> Response<Body> {
+    let mut headers = serde_json::Map::new();
+    for name in req.headers().keys() {
+        let values: Vec<String> = req
+            .headers()
+            .get_all(name)
+            .iter()
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+            .collect();
+        headers.insert(name.to_string(), values.into());
+    }
+
+    let body = serde_json::json!({
+        "client_ip": client_ip.to_string(),
+        "method": req.method().as_str(),
+        "uri": req.uri().to_string(),
+        "version": format!("{:?}", req.version()),
+        "headers": headers,
+    });
+
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string_pretty(&body).unwrap()))
+        .unwrap()
+}
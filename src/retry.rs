@@ -0,0 +1,271 @@
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Method, Request, Response};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::balancer::random_u64;
+use crate::config::{RetryBudget, RetryOn, RetryPolicy};
+use crate::upstream::{self, UpstreamError};
+
+/// Length of one budget window. The previous window counts too, so the
+/// budget looks back between one and two windows.
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// The global retry budget, shared by all routes.
+pub struct Budget {
+    config: RetryBudget,
+    window: Mutex<Window>,
+}
+
+struct Window {
+    start: Instant,
+    requests: u64,
+    retries: u64,
+    previous_requests: u64,
+    previous_retries: u64,
+}
+
+impl Budget {
+    pub fn new(config: &RetryBudget) -> Self {
+        Budget {
+            config: config.clone(),
+            window: Mutex::new(Window {
+                start: Instant::now(),
+                requests: 0,
+                retries: 0,
+                previous_requests: 0,
+                previous_retries: 0,
+            }),
+        }
+    }
+
+    /// Counts a request forwarded to an upstream, which adds to the budget.
+    pub fn record_request(&self) {
+        self.current().requests += 1;
+    }
+
+    /// Takes one retry out of the budget, or returns `false` if it is spent.
+    pub fn try_retry(&self) -> bool {
+        let mut window = self.current();
+        let requests = window.requests + window.previous_requests;
+        let retries = window.retries + window.previous_retries;
+        let allowed = u64::from(self.config.min_per_second) * WINDOW.as_secs()
+            + requests * u64::from(self.config.percent) / 100;
+        if retries >= allowed {
+            return false;
+        }
+        window.retries += 1;
+        true
+    }
+
+    /// The window for now, rolled over if it has run out.
+    fn current(&self) -> std::sync::MutexGuard<'_, Window> {
+        let mut window = self.window.lock().unwrap();
+        let elapsed = window.start.elapsed();
+        if elapsed >= WINDOW {
+            let skipped_one = elapsed < WINDOW * 2;
+            window.previous_requests = if skipped_one { window.requests } else { 0 };
+            window.previous_retries = if skipped_one { window.retries } else { 0 };
+            window.requests = 0;
+            window.retries = 0;
+            window.start = Instant::now();
+        }
+        window
+    }
+}
+
+/// Whether `req` may be retried under `policy` at all, judged by its method
+/// and declared body size. Bodies of unknown size, chunked or over HTTP/2,
+/// are eligible until `buffer` finds them too large. Protocol upgrades are
+/// never retried, since the client's connection can only be handed over
+/// once.
+pub fn is_eligible(policy: &RetryPolicy, req: &Request<Body>) -> bool {
+    if !policy.non_idempotent && !is_idempotent(req.method()) {
+        return false;
+    }
+    if upstream::upgrade_protocol(req.headers()).is_some() {
+        return false;
+    }
+    match req.headers().get(hyper::header::CONTENT_LENGTH) {
+        Some(len) => len
+            .to_str()
+            .ok()
+            .and_then(|len| len.parse::<u64>().ok())
+            .is_some_and(|len| len <= policy.max_body_bytes),
+        None => true,
+    }
+}
+
+/// A request body read ahead so the request can be retried.
+pub enum Buffered {
+    /// The whole body, to be sent as often as needed.
+    Complete(Bytes),
+    /// A body larger than the limit, put back together to be sent once.
+    TooLarge(Body),
+}
+
+/// Reads `body` into memory, but no more than `limit` bytes of it.
+pub async fn buffer(mut body: Body, limit: u64) -> Result<Buffered, hyper::Error> {
+    let mut read = Vec::new();
+    let mut len = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        len += chunk.len() as u64;
+        read.push(chunk);
+        if len > limit {
+            return Ok(Buffered::TooLarge(replay(read, body)));
+        }
+    }
+    Ok(Buffered::Complete(Bytes::from(read.concat())))
+}
+
+/// `rest` with the chunks already `read` from it in front again.
+fn replay(read: Vec<Bytes>, mut rest: Body) -> Body {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        for chunk in read {
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+        while let Some(chunk) = rest.data().await {
+            match chunk {
+                Ok(chunk) => {
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Err(_) => {
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+        if let Ok(Some(trailers)) = rest.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    body
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// Classifies the outcome of one attempt, or `None` if it succeeded.
+pub fn failure(result: &Result<Response<Body>, UpstreamError>) -> Option<RetryOn> {
+    match result {
+        Ok(response) if response.status().is_server_error() => Some(RetryOn::ServerError),
+        Ok(_) => None,
+        Err(UpstreamError::Connect(_)) => Some(RetryOn::ConnectFailure),
+        Err(UpstreamError::Reset(_)) => Some(RetryOn::Reset),
+        Err(UpstreamError::Timeout(_)) => Some(RetryOn::Timeout),
+        Err(UpstreamError::Http(_) | UpstreamError::Request(_)) => None,
+    }
+}
+
+/// Delay before retry number `retry` (starting at 1): exponential backoff
+/// with full jitter, so clients retrying together spread out.
+pub fn backoff(policy: &RetryPolicy, retry: u32) -> Duration {
+    let ceiling = policy
+        .backoff_ms
+        .saturating_mul(1 << retry.saturating_sub(1).min(16))
+        .min(policy.max_backoff_ms);
+    Duration::from_millis(random_u64() % (ceiling + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProxyConfig;
+    use crate::proxy;
+    use crate::router::Router;
+    use hyper::server::conn::AddrStream;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Server, StatusCode};
+    use std::convert::Infallible;
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    #[test]
+    fn budget_allows_percent_plus_minimum() {
+        let budget = Budget::new(&RetryBudget {
+            percent: 20,
+            min_per_second: 1,
+        });
+        for _ in 0..50 {
+            budget.record_request();
+        }
+        // 1/s over the 10s window, plus 20% of 50 requests.
+        for _ in 0..20 {
+            assert!(budget.try_retry());
+        }
+        assert!(!budget.try_retry());
+
+        for _ in 0..5 {
+            budget.record_request();
+        }
+        assert!(budget.try_retry());
+        assert!(!budget.try_retry());
+    }
+
+    #[tokio::test]
+    async fn large_chunked_bodies_are_sent_once_and_intact() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let bodies = Arc::clone(&received);
+        let make_svc = make_service_fn(move |_: &AddrStream| {
+            let bodies = Arc::clone(&bodies);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let bodies = Arc::clone(&bodies);
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        bodies.lock().unwrap().push(body);
+                        let mut response = Response::new(Body::empty());
+                        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let upstream = format!("http://127.0.0.1:{}", server.local_addr().port());
+        tokio::spawn(server);
+
+        let config: ProxyConfig = serde_json::from_value(serde_json::json!({
+            "routes": { "/": {
+                "target": upstream,
+                "retry": { "attempts": 3, "retry_on": ["5xx"], "backoff_ms": 1, "max_body_bytes": 8 },
+            } },
+        }))
+        .unwrap();
+        let router = Arc::new(Router::new(&config).unwrap());
+        let send = |chunks: &'static [&'static str]| {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                for chunk in chunks {
+                    sender
+                        .send_data(Bytes::from_static(chunk.as_bytes()))
+                        .await
+                        .unwrap();
+                }
+            });
+            let req = Request::put("/").body(body).unwrap();
+            proxy::handle_request(IpAddr::from([127, 0, 0, 1]), req, Arc::clone(&router))
+        };
+
+        let response = send(&["small"]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(received.lock().unwrap().len(), 3);
+
+        received.lock().unwrap().clear();
+        let response = send(&["0123", "4567", "89ab", "cdef"]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(&received[0][..], b"0123456789abcdef");
+    }
+}
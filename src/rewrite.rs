@@ -0,0 +1,257 @@
+use crate::config::ProxyRoute;
+use crate::connector;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::http::uri::{Authority, Scheme};
+use hyper::Uri;
+use std::fmt;
+
+/// Why a request could not be rewritten for its upstream.
+#[derive(Debug)]
+pub enum RewriteError {
+    Target(String),
+    Uri(String),
+    Header(String),
+}
+
+impl fmt::Display for RewriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RewriteError::Target(reason) => write!(f, "invalid target URL: {}", reason),
+            RewriteError::Uri(reason) => {
+                write!(f, "could not build upstream URI: {}", reason)
+            }
+            RewriteError::Header(reason) => write!(f, "invalid request header: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for RewriteError {}
+
+/// The parts of a route target that every forwarded request is built from.
+pub struct Target {
+    scheme: Scheme,
+    authority: Authority,
+    /// Path component of the target without a trailing slash, e.g. `/v1`.
+    base_path: String,
+}
+
+impl Target {
+    /// Parses an `http://` target, or a `unix:/path/to.sock` one.
+    pub fn parse(target: &str) -> Result<Self, RewriteError> {
+        let uri: Uri = connector::unix_url(target)
+            .as_deref()
+            .unwrap_or(target)
+            .parse()
+            .map_err(|e| RewriteError::Target(format!("{} ({})", target, e)))?;
+        let parts = uri.into_parts();
+
+        let scheme = parts
+            .scheme
+            .ok_or_else(|| RewriteError::Target(format!("{} has no scheme", target)))?;
+        let authority = parts
+            .authority
+            .ok_or_else(|| RewriteError::Target(format!("{} has no host", target)))?;
+        let base_path = parts
+            .path_and_query
+            .map(|pq| pq.path().trim_end_matches('/').to_string())
+            .unwrap_or_default();
+
+        Ok(Target {
+            scheme,
+            authority,
+            base_path,
+        })
+    }
+
+    /// Builds the upstream URI for `path`, keeping `query` and the
+    /// percent-encoding of both exactly as received. An empty `path` leaves
+    /// the target's own path as it is.
+    pub fn uri(&self, path: &str, query: Option<&str>) -> Result<Uri, RewriteError> {
+        let mut path_and_query = format!("{}{}", self.base_path, path);
+        if path_and_query.is_empty() {
+            path_and_query.push('/');
+        }
+        if let Some(query) = query {
+            path_and_query.push('?');
+            path_and_query.push_str(query);
+        }
+
+        Uri::builder()
+            .scheme(self.scheme.clone())
+            .authority(self.authority.clone())
+            .path_and_query(path_and_query)
+            .build()
+            .map_err(|e| RewriteError::Uri(e.to_string()))
+    }
+}
+
+/// Parses the expanded target of a pattern route. A target without a path
+/// of its own, like `http://host:9000`, gets the request's `path`. The
+/// request's `query` is carried over unless the template produced a query
+/// of its own.
+pub fn template_uri(url: &str, path: &str, query: Option<&str>) -> Result<Uri, RewriteError> {
+    let (base, own_query) = match url.split_once('?') {
+        Some((base, own_query)) => (base, Some(own_query)),
+        None => (url, None),
+    };
+    let mut url = connector::unix_url(base).unwrap_or_else(|| base.to_string());
+    let has_path = url
+        .split_once("://")
+        .is_some_and(|(_, rest)| rest.contains('/'));
+    if !has_path {
+        url.push_str(path);
+    }
+    if let Some(query) = own_query.or(query) {
+        url.push('?');
+        url.push_str(query);
+    }
+    let uri: Uri = url
+        .parse()
+        .map_err(|e| RewriteError::Uri(format!("{} ({})", url, e)))?;
+
+    if uri.scheme().is_none() || uri.authority().is_none() {
+        return Err(RewriteError::Target(format!(
+            "{} needs a scheme and host",
+            url
+        )));
+    }
+    Ok(uri)
+}
+
+/// Sets the route's `request_headers` on the forwarded request, replacing
+/// any value the client sent.
+pub fn set_headers(
+    headers: &mut HeaderMap,
+    values: &[(String, String)],
+) -> Result<(), RewriteError> {
+    for (name, value) in values {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| RewriteError::Header(format!("{} ({})", name, e)))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|e| RewriteError::Header(format!("{}: {} ({})", name, value, e)))?;
+        headers.insert(name, value);
+    }
+    Ok(())
+}
+
+/// Applies a prefix route's rewrite policy. `original` is the request path
+/// and `stripped` what is left of it after the matched prefix.
+pub fn rewrite_path(route: &ProxyRoute, original: &str, stripped: &str) -> String {
+    let path = match (&route.replace_path, route.strip_prefix.unwrap_or(true)) {
+        (Some(replacement), _) => replacement.as_str(),
+        (None, true) => stripped,
+        (None, false) => original,
+    };
+
+    match route.add_prefix.as_deref().map(|p| p.trim_end_matches('/')) {
+        Some(prefix) if !prefix.is_empty() => {
+            let slash = if prefix.starts_with('/') { "" } else { "/" };
+            if path == "/" || path.is_empty() {
+                format!("{}{}", slash, prefix)
+            } else {
+                format!("{}{}{}", slash, prefix, path)
+            }
+        }
+        _ => path.to_string(),
+    }
+}
+
+/// Shows how paths under `prefix` reach the upstream, e.g.
+/// `/api/… → /internal/api/…`.
+pub fn describe(route: &ProxyRoute, prefix: &str) -> String {
+    let original = format!("{}/…", prefix);
+    let path = rewrite_path(route, &original, "/…");
+    format!("↳ {} → {}", original, path)
+}
+
+/// Decodes `%XX` escapes in a path segment or, with `plus_as_space`, in an
+/// `application/x-www-form-urlencoded` query component, where `+` stands
+/// for a space. Returns `None` for malformed escapes or non-UTF-8 results.
+pub fn percent_decode(input: &str, plus_as_space: bool) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3)?;
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                let hex = std::str::from_utf8(hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+                continue;
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_paths_and_queries() {
+        assert_eq!(percent_decode("a%20b+c", false).as_deref(), Some("a b+c"));
+        assert_eq!(percent_decode("a%20b+c", true).as_deref(), Some("a b c"));
+        assert_eq!(percent_decode("%2e%2E%2f", false).as_deref(), Some("../"));
+        assert_eq!(percent_decode("%C3%A9", true).as_deref(), Some("é"));
+        assert_eq!(percent_decode("%zz", true), None);
+        assert_eq!(percent_decode("%+1", false), None);
+        assert_eq!(percent_decode("50%", false), None);
+        assert_eq!(percent_decode("%ff", false), None);
+    }
+
+    fn expanded(pattern: &str, target: &str, uri: &str) -> String {
+        let uri: Uri = uri.parse().unwrap();
+        let regex = if pattern.starts_with('^') {
+            regex::Regex::new(pattern).unwrap()
+        } else {
+            crate::pattern::compile_path_template(pattern).unwrap()
+        };
+        let caps = regex.captures(uri.path()).unwrap();
+        let caps = crate::pattern::Captures::new(&regex, &caps);
+        let target = crate::pattern::expand(target, &caps);
+        template_uri(&target, uri.path(), uri.query())
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn pattern_targets_expand_into_upstream_uris() {
+        let numbered = "http://up.test/api/v$1/$2";
+        assert_eq!(
+            expanded(r"^/v(\d+)/(.*)$", numbered, "/v2/users/7?page=3"),
+            "http://up.test/api/v2/users/7?page=3"
+        );
+        assert_eq!(
+            expanded(
+                "/users/{id}/avatar",
+                "http://up.test/avatars/{id}.png",
+                "/users/42/avatar"
+            ),
+            "http://up.test/avatars/42.png"
+        );
+        // No path in the target: the request path goes through unchanged.
+        assert_eq!(
+            expanded("/users/{id}", "http://up.test", "/users/42?full=1"),
+            "http://up.test/users/42?full=1"
+        );
+        // The target's own query replaces the request's.
+        assert_eq!(
+            expanded(
+                "/users/{id}",
+                "http://up.test/u?id={id}",
+                "/users/42?full=1"
+            ),
+            "http://up.test/u?id=42"
+        );
+    }
+}
@@ -0,0 +1,568 @@
+use crate::balancer::Balancer;
+use crate::config::{Fallback, MatchMode, ProxyConfig, RedirectRoute, Route, RouteAction};
+use crate::dns::Dns;
+use crate::error_page;
+use crate::mock;
+use crate::pattern::{self, Captures};
+use crate::pool::PoolStats;
+use crate::retry::Budget;
+use crate::upstream::{Transports, UpstreamClient};
+use hyper::{Body, Request};
+use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Host part of a route, compiled from its `host` field.
+enum HostPattern {
+    Exact(String),
+    /// `*.example.test`, stored as `.example.test`. Matches any subdomain but
+    /// not `example.test` itself.
+    Wildcard(String),
+}
+
+impl HostPattern {
+    fn parse(host: &str) -> Self {
+        let host = host.trim().trim_end_matches('.').to_lowercase();
+        match host.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => HostPattern::Wildcard(suffix.to_string()),
+            _ => HostPattern::Exact(host),
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(name) => host == name,
+            HostPattern::Wildcard(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+        }
+    }
+
+    /// Exact names are more specific than wildcards, and longer wildcard
+    /// suffixes are more specific than shorter ones.
+    fn specificity(&self) -> (u8, usize) {
+        match self {
+            HostPattern::Exact(_) => (2, 0),
+            HostPattern::Wildcard(suffix) => (1, suffix.len()),
+        }
+    }
+}
+
+/// How a route matches the request path.
+enum PathMatcher {
+    /// Prefix as it is compared against request paths. In segment mode
+    /// trailing slashes are dropped so `/api` and `/api/` behave the same.
+    Prefix(String),
+    /// A `regex` route or a parameterized `path` like `/users/{id}`.
+    Pattern(Regex),
+}
+
+/// Result of a successful route lookup.
+pub struct RouteMatch<'a> {
+    pub entry: &'a CompiledRoute,
+    /// What is left of the path after a prefix route's prefix, always
+    /// starting with `/`. `None` for pattern routes.
+    pub rest: Option<String>,
+    /// Groups captured by a pattern route.
+    pub captures: Captures,
+}
+
+/// A single entry of the compiled routing table.
+pub struct CompiledRoute {
+    pub name: String,
+    pub route: Route,
+    /// Present for proxy routes.
+    pub balancer: Option<Arc<Balancer>>,
+    /// Present for proxy routes.
+    pub client: Option<Arc<UpstreamClient>>,
+    host: Option<HostPattern>,
+    path: PathMatcher,
+}
+
+impl CompiledRoute {
+    fn new(
+        name: &str,
+        route: &Route,
+        config: &ProxyConfig,
+        transports: &Transports,
+    ) -> Result<Self, String> {
+        let prefix = route.path(name);
+        let path = if let Some(regex) = &route.regex {
+            let regex = Regex::new(regex).map_err(|e| format!("{}: invalid regex: {}", name, e))?;
+            PathMatcher::Pattern(regex)
+        } else if pattern::is_path_template(prefix) {
+            let regex = pattern::compile_path_template(prefix)
+                .map_err(|e| format!("{}: invalid path template {}: {}", name, prefix, e))?;
+            PathMatcher::Pattern(regex)
+        } else {
+            PathMatcher::Prefix(match route.match_mode {
+                MatchMode::Segment => prefix.trim_end_matches('/').to_string(),
+                MatchMode::RawPrefix => prefix.to_string(),
+            })
+        };
+
+        match &route.action {
+            RouteAction::Redirect(redirect) => check_redirect(redirect),
+            RouteAction::Mock(mock) => mock::check(mock),
+            _ => Ok(()),
+        }
+        .map_err(|e| format!("{}: {}", name, e))?;
+
+        let (balancer, client) = match &route.action {
+            RouteAction::Proxy(proxy) => (
+                Some(Arc::new(
+                    Balancer::new(proxy).map_err(|e| format!("{}: {}", name, e))?,
+                )),
+                Some(Arc::new(
+                    UpstreamClient::new(proxy, config, transports)
+                        .map_err(|e| format!("{}: {}", name, e))?,
+                )),
+            ),
+            _ => (None, None),
+        };
+
+        Ok(CompiledRoute {
+            name: name.to_string(),
+            route: route.clone(),
+            balancer,
+            client,
+            host: route.host.as_deref().map(HostPattern::parse),
+            path,
+        })
+    }
+
+    /// `host/path` for routes bound to a host, otherwise just the path.
+    /// Regex routes show their pattern prefixed with `~`.
+    pub fn label(&self) -> String {
+        let path = match &self.route.regex {
+            Some(regex) => format!("~{}", regex),
+            None => self.route.path(&self.name).to_string(),
+        };
+        match &self.route.host {
+            Some(host) => format!("{}{}", host, path),
+            None => path.to_string(),
+        }
+    }
+
+    /// Prefix this route matches on, or `None` for pattern routes.
+    pub fn prefix(&self) -> Option<&str> {
+        match &self.path {
+            PathMatcher::Prefix(prefix) => Some(prefix),
+            PathMatcher::Pattern(_) => None,
+        }
+    }
+
+    fn matches_host(&self, host: Option<&str>) -> bool {
+        match (&self.host, host) {
+            (None, _) => true,
+            (Some(pattern), Some(host)) => pattern.matches(host),
+            (Some(_), None) => false,
+        }
+    }
+
+    fn matches_predicates(&self, req: &Request<Body>) -> bool {
+        self.route.predicates.iter().all(|p| p.matches(req))
+    }
+
+    /// Matches `path` against this route, returning the remaining path for
+    /// prefix routes and the captured groups for pattern routes.
+    fn match_path(&self, path: &str) -> Option<(Option<String>, Captures)> {
+        match &self.path {
+            PathMatcher::Prefix(prefix) => {
+                let rest = path.strip_prefix(prefix.as_str())?;
+                if self.route.match_mode == MatchMode::Segment
+                    && !rest.is_empty()
+                    && !rest.starts_with('/')
+                {
+                    return None;
+                }
+                Some((Some(normalize_path(rest)), Captures::default()))
+            }
+            PathMatcher::Pattern(regex) => {
+                let caps = regex.captures(path)?;
+                Some((None, Captures::new(regex, &caps)))
+            }
+        }
+    }
+
+    /// Within a priority, pattern routes sort before prefix routes, and longer
+    /// prefixes before shorter ones.
+    fn path_specificity(&self) -> (u8, usize) {
+        match &self.path {
+            PathMatcher::Pattern(_) => (1, 0),
+            PathMatcher::Prefix(prefix) => (0, prefix.len()),
+        }
+    }
+
+    fn specificity(&self, other: &Self) -> Ordering {
+        let host = |entry: &Self| entry.host.as_ref().map(HostPattern::specificity);
+
+        host(other)
+            .cmp(&host(self))
+            .then_with(|| other.route.priority().cmp(&self.route.priority()))
+            .then_with(|| other.path_specificity().cmp(&self.path_specificity()))
+            .then_with(|| {
+                let predicates = |entry: &Self| entry.route.predicates.len();
+                predicates(other).cmp(&predicates(self))
+            })
+            .then_with(|| self.name.cmp(&other.name))
+    }
+}
+
+/// Routing table built once at startup from the configured routes.
+///
+/// Entries are kept sorted so that the first matching entry is the one that
+/// should win: routes bound to a host come before the catch-all routes
+/// without one, then higher `priority` comes first. Within a priority,
+/// pattern routes come before prefix routes, longer prefixes before shorter
+/// ones, and routes with more predicates before routes with fewer. The route
+/// name is used as a final tie-breaker so the order never depends on the
+/// config's `HashMap` iteration order.
+pub struct Router {
+    routes: Vec<CompiledRoute>,
+    fallback: Fallback,
+    /// Present when the fallback is a proxy.
+    fallback_balancer: Option<Arc<Balancer>>,
+    fallback_client: Option<Arc<UpstreamClient>>,
+    dns: Arc<Dns>,
+    transports: Transports,
+    retry_budget: Budget,
+    error_pages: HashMap<u16, String>,
+}
+
+impl Router {
+    pub fn new(config: &ProxyConfig) -> Result<Self, String> {
+        let dns = Arc::new(Dns::new(&config.dns)?);
+        let transports = Transports::new(&config.pool, &dns);
+        let mut routes = config
+            .routes
+            .iter()
+            .map(|(name, route)| CompiledRoute::new(name, route, config, &transports))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        routes.sort_by(CompiledRoute::specificity);
+        error_page::check(&config.error_pages).map_err(|e| format!("error_pages: {}", e))?;
+
+        let (fallback_balancer, fallback_client) = match &config.fallback {
+            Fallback::Redirect(redirect) => {
+                check_redirect(redirect).map_err(|e| format!("fallback: {}", e))?;
+                (None, None)
+            }
+            Fallback::Proxy(proxy) => (
+                Some(Arc::new(
+                    Balancer::new(proxy).map_err(|e| format!("fallback: {}", e))?,
+                )),
+                Some(Arc::new(
+                    UpstreamClient::new(proxy, config, &transports)
+                        .map_err(|e| format!("fallback: {}", e))?,
+                )),
+            ),
+            Fallback::NotFound => (None, None),
+        };
+
+        Ok(Router {
+            routes,
+            fallback: config.fallback.clone(),
+            fallback_balancer,
+            fallback_client,
+            dns,
+            transports,
+            retry_budget: Budget::new(&config.retry_budget),
+            error_pages: config.error_pages.clone(),
+        })
+    }
+
+    /// Returns the most specific route matching `req` and where to forward
+    /// it. Predicates are only checked once host and path matched; if they
+    /// fail the next candidate is tried.
+    pub fn find(&self, req: &Request<Body>) -> Option<RouteMatch<'_>> {
+        let host = request_host(req);
+        let path = req.uri().path();
+
+        self.routes.iter().find_map(|entry| {
+            if !entry.matches_host(host.as_deref()) {
+                return None;
+            }
+            let (rest, captures) = entry.match_path(path)?;
+            if !entry.matches_predicates(req) {
+                return None;
+            }
+            Some(RouteMatch {
+                entry,
+                rest,
+                captures,
+            })
+        })
+    }
+
+    pub fn routes(&self) -> &[CompiledRoute] {
+        &self.routes
+    }
+
+    pub fn fallback(&self) -> &Fallback {
+        &self.fallback
+    }
+
+    pub fn fallback_balancer(&self) -> Option<&Arc<Balancer>> {
+        self.fallback_balancer.as_ref()
+    }
+
+    pub fn fallback_client(&self) -> Option<&Arc<UpstreamClient>> {
+        self.fallback_client.as_ref()
+    }
+
+    /// The balancer and client of every proxy route, the fallback included.
+    pub fn proxies(&self) -> impl Iterator<Item = (&Arc<Balancer>, &Arc<UpstreamClient>)> {
+        self.routes
+            .iter()
+            .filter_map(|entry| Some((entry.balancer.as_ref()?, entry.client.as_ref()?)))
+            .chain(self.fallback_balancer().zip(self.fallback_client()))
+    }
+
+    /// Connection counts for each upstream host, across all routes.
+    pub fn pool_stats(&self) -> Vec<PoolStats> {
+        self.transports.pool_stats()
+    }
+
+    pub fn dns(&self) -> &Arc<Dns> {
+        &self.dns
+    }
+
+    pub fn retry_budget(&self) -> &Budget {
+        &self.retry_budget
+    }
+
+    pub fn error_pages(&self) -> &HashMap<u16, String> {
+        &self.error_pages
+    }
+}
+
+fn check_redirect(redirect: &RedirectRoute) -> Result<(), String> {
+    if RedirectRoute::STATUSES.contains(&redirect.status) {
+        Ok(())
+    } else {
+        Err(format!(
+            "redirect status must be one of {:?}, got {}",
+            RedirectRoute::STATUSES,
+            redirect.status
+        ))
+    }
+}
+
+fn normalize_path(rest: &str) -> String {
+    if rest.starts_with('/') {
+        rest.to_string()
+    } else {
+        format!("/{}", rest)
+    }
+}
+
+/// Host the request was addressed to, lowercased and without a port.
+fn request_host(req: &Request<Body>) -> Option<String> {
+    let host = match req.uri().host() {
+        Some(host) => host,
+        None => req.headers().get(hyper::header::HOST)?.to_str().ok()?,
+    };
+
+    let host = if let Some(bracketed) = host.strip_prefix('[') {
+        bracketed.split(']').next().unwrap_or(bracketed)
+    } else {
+        host.rsplit_once(':').map_or(host, |(name, _port)| name)
+    };
+
+    Some(host.trim_end_matches('.').to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(routes: serde_json::Value) -> Router {
+        let config: ProxyConfig =
+            serde_json::from_value(serde_json::json!({ "routes": routes })).unwrap();
+        Router::new(&config).unwrap()
+    }
+
+    /// The name of the route `uri` goes to, with `headers` set.
+    fn route_for(router: &Router, uri: &str, headers: &[(&str, &str)]) -> Option<String> {
+        let mut req = Request::get(uri);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let req = req.body(Body::empty()).unwrap();
+        router.find(&req).map(|found| found.entry.name.clone())
+    }
+
+    fn names(router: &Router) -> Vec<&str> {
+        router.routes().iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn longest_prefix_wins_in_any_order() {
+        let routes = serde_json::json!({
+            "/api": "http://a.test",
+            "/api/v2": "http://b.test",
+            "/": "http://c.test",
+        });
+        let first = router(routes.clone());
+        // Each router iterates its config's map in a different order.
+        for _ in 0..20 {
+            let router = router(routes.clone());
+            assert_eq!(names(&router), names(&first));
+            assert_eq!(
+                route_for(&router, "/api/v2/users", &[]).as_deref(),
+                Some("/api/v2")
+            );
+            assert_eq!(route_for(&router, "/api/v1", &[]).as_deref(), Some("/api"));
+            assert_eq!(route_for(&router, "/other", &[]).as_deref(), Some("/"));
+        }
+    }
+
+    #[test]
+    fn priority_then_name_break_ties() {
+        let router = router(serde_json::json!({
+            "b": { "target": "http://b.test", "path": "/same" },
+            "a": { "target": "http://a.test", "path": "/same" },
+            "c": { "target": "http://c.test", "path": "/same", "priority": 10 },
+        }));
+        assert_eq!(names(&router), ["c", "a", "b"]);
+        assert_eq!(route_for(&router, "/same", &[]).as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        let router = router(serde_json::json!({
+            "/api": "http://a.test",
+            "/docs/": "http://b.test",
+            "raw": { "target": "http://c.test", "path": "/app", "match": "raw_prefix" },
+        }));
+        assert_eq!(route_for(&router, "/api", &[]).as_deref(), Some("/api"));
+        assert_eq!(route_for(&router, "/api/", &[]).as_deref(), Some("/api"));
+        assert_eq!(
+            route_for(&router, "/api/users", &[]).as_deref(),
+            Some("/api")
+        );
+        assert_eq!(route_for(&router, "/apiary", &[]), None);
+        assert_eq!(route_for(&router, "/api-docs", &[]), None);
+
+        // A trailing slash on the prefix makes no difference.
+        assert_eq!(route_for(&router, "/docs", &[]).as_deref(), Some("/docs/"));
+        assert_eq!(route_for(&router, "/docs/", &[]).as_deref(), Some("/docs/"));
+        assert_eq!(route_for(&router, "/docsite", &[]), None);
+
+        assert_eq!(route_for(&router, "/apple", &[]).as_deref(), Some("raw"));
+        assert_eq!(route_for(&router, "/app/x", &[]).as_deref(), Some("raw"));
+    }
+
+    #[test]
+    fn remaining_path_starts_with_a_slash() {
+        let router = router(serde_json::json!({ "/api": "http://a.test" }));
+        let rest = |path: &str| {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            router.find(&req).unwrap().rest
+        };
+        assert_eq!(rest("/api").as_deref(), Some("/"));
+        assert_eq!(rest("/api/").as_deref(), Some("/"));
+        assert_eq!(rest("/api/users").as_deref(), Some("/users"));
+    }
+
+    #[test]
+    fn wildcard_hosts_match_subdomains_only() {
+        let wildcard = HostPattern::parse("*.Example.test");
+        assert!(wildcard.matches("api.example.test"));
+        assert!(wildcard.matches("a.b.example.test"));
+        assert!(!wildcard.matches("example.test"));
+        assert!(!wildcard.matches("badexample.test"));
+    }
+
+    #[test]
+    fn request_host_drops_port_and_case() {
+        let host = |value: &str| {
+            let req = Request::get("/")
+                .header(hyper::header::HOST, value)
+                .body(Body::empty())
+                .unwrap();
+            request_host(&req)
+        };
+        assert_eq!(
+            host("API.Example.Test:8080").as_deref(),
+            Some("api.example.test")
+        );
+        assert_eq!(host("app.localhost.").as_deref(), Some("app.localhost"));
+        assert_eq!(host("[::1]:8080").as_deref(), Some("::1"));
+        assert_eq!(host("[fd00::1]").as_deref(), Some("fd00::1"));
+
+        // Absolute-form request targets take precedence over the header.
+        let req = Request::get("http://Other.test:81/")
+            .header(hyper::header::HOST, "ignored.test")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(request_host(&req).as_deref(), Some("other.test"));
+    }
+
+    #[test]
+    fn host_routes_fall_through_to_catch_all() {
+        let router = router(serde_json::json!({
+            "wild": { "target": "http://a.test", "host": "*.example.test", "path": "/" },
+            "exact": { "target": "http://b.test", "host": "api.example.test", "path": "/api" },
+            "ipv6": { "target": "http://c.test", "host": "::1", "path": "/" },
+            "/": "http://d.test",
+        }));
+        let host = |value: &str, path: &str| route_for(&router, path, &[("host", value)]);
+
+        assert_eq!(
+            host("API.example.test:8443", "/api/x").as_deref(),
+            Some("exact")
+        );
+        // The exact host has no route for this path, so the wildcard takes it.
+        assert_eq!(host("api.example.test", "/other").as_deref(), Some("wild"));
+        assert_eq!(host("www.Example.Test:8080", "/").as_deref(), Some("wild"));
+        assert_eq!(host("example.test", "/").as_deref(), Some("/"));
+        assert_eq!(host("[::1]:8000", "/").as_deref(), Some("ipv6"));
+        assert_eq!(route_for(&router, "/", &[]).as_deref(), Some("/"));
+    }
+
+    #[test]
+    fn failed_predicates_fall_through() {
+        let router = router(serde_json::json!({
+            "qa": {
+                "target": "http://qa.test",
+                "path": "/app",
+                "predicates": [{ "header": "x-env", "value": "qa" }, { "cookie": "beta" }],
+            },
+            "posts": {
+                "target": "http://posts.test",
+                "path": "/app",
+                "predicates": [{ "method": "POST" }],
+            },
+            "/app": "http://app.test",
+        }));
+        let qa = [("x-env", "qa"), ("cookie", "beta=1")];
+        assert_eq!(route_for(&router, "/app/x", &qa).as_deref(), Some("qa"));
+        assert_eq!(
+            route_for(&router, "/app/x", &qa[..1]).as_deref(),
+            Some("/app")
+        );
+        assert_eq!(
+            route_for(&router, "/app/x", &[("x-env", "prod")]).as_deref(),
+            Some("/app")
+        );
+    }
+
+    #[test]
+    fn priority_outranks_pattern_routes() {
+        let router = router(serde_json::json!({
+            "pattern": { "target": "http://a.test/$1", "regex": "^/api/(.*)$" },
+            "/api/users": { "target": "http://b.test", "priority": 5 },
+            "/api": "http://c.test",
+        }));
+        assert_eq!(names(&router), ["/api/users", "pattern", "/api"]);
+        assert_eq!(
+            route_for(&router, "/api/users/1", &[]).as_deref(),
+            Some("/api/users")
+        );
+        assert_eq!(
+            route_for(&router, "/api/other", &[]).as_deref(),
+            Some("pattern")
+        );
+    }
+}
@@ -0,0 +1,392 @@
+use hyper::header::{
+    HeaderValue, ACCEPT_RANGES, ALLOW, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, LOCATION, RANGE,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::config::StaticRoute;
+use crate::error_page::{self, Format};
+use crate::rewrite::percent_decode;
+
+const INDEX_FILE: &str = "index.html";
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Serves `path`, the part of the request path below the route's prefix
+/// (empty for a request to the prefix itself), from the route's `root`.
+/// Errors are answered with the error page for their status, naming the
+/// route `label`.
+pub async fn serve(
+    route: &StaticRoute,
+    req: &Request<Body>,
+    path: &str,
+    label: &str,
+    pages: &HashMap<u16, String>,
+) -> Response<Body> {
+    let errors = Errors {
+        label,
+        format: Format::of(req),
+        pages,
+    };
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        let reason = format!("{} is not allowed here.", req.method());
+        let mut response = errors
+            .respond(StatusCode::METHOD_NOT_ALLOWED, &reason)
+            .await;
+        response
+            .headers_mut()
+            .insert(ALLOW, HeaderValue::from_static("GET, HEAD"));
+        return response;
+    }
+
+    let not_found = format!("No file matches {}.", req.uri().path());
+    let root = match tokio::fs::canonicalize(&route.root).await {
+        Ok(root) => root,
+        Err(error) => {
+            eprintln!("❌ Static root {}: {}", route.root, error);
+            return errors.respond(StatusCode::NOT_FOUND, &not_found).await;
+        }
+    };
+
+    let file = match resolve(&root, path).await {
+        Some(Resolved::File(file)) => file,
+        Some(Resolved::Directory) => {
+            // Relative links in an index page only work below a trailing slash.
+            let location = match req.uri().query() {
+                Some(query) => format!("{}/?{}", req.uri().path(), query),
+                None => format!("{}/", req.uri().path()),
+            };
+            return Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(LOCATION, location)
+                .body(Body::empty())
+                .unwrap();
+        }
+        None if route.spa => match resolve(&root, INDEX_FILE).await {
+            Some(Resolved::File(index)) => index,
+            _ => return errors.respond(StatusCode::NOT_FOUND, &not_found).await,
+        },
+        None => return errors.respond(StatusCode::NOT_FOUND, &not_found).await,
+    };
+
+    send_file(req, &file, &errors).await
+}
+
+/// Where a static route's errors go.
+struct Errors<'a> {
+    label: &'a str,
+    format: Format,
+    pages: &'a HashMap<u16, String>,
+}
+
+impl Errors<'_> {
+    async fn respond(&self, status: StatusCode, reason: &str) -> Response<Body> {
+        error_page::respond(status, Some(self.label), reason, self.format, self.pages).await
+    }
+}
+
+enum Resolved {
+    File(PathBuf),
+    /// A directory requested without a trailing slash.
+    Directory,
+}
+
+/// Maps a request path onto a file below `root`.
+///
+/// Segments are percent-decoded one by one and rejected if they decode to
+/// `..` or contain a separator, so neither `/../` nor `%2e%2e%2f` can leave
+/// the root. The result is canonicalized and checked against `root` again,
+/// which also catches symlinks pointing outside of it.
+async fn resolve(root: &Path, path: &str) -> Option<Resolved> {
+    let mut candidate = root.to_path_buf();
+    for segment in path.split('/') {
+        let segment = percent_decode(segment, false)?;
+        match segment.as_str() {
+            "" | "." => continue,
+            ".." => return None,
+            _ if segment.contains(['/', '\\', '\0']) => return None,
+            _ => candidate.push(segment),
+        }
+    }
+
+    let candidate = tokio::fs::canonicalize(&candidate).await.ok()?;
+    if !candidate.starts_with(root) {
+        return None;
+    }
+
+    let metadata = tokio::fs::metadata(&candidate).await.ok()?;
+    if metadata.is_file() {
+        return Some(Resolved::File(candidate));
+    }
+    if !metadata.is_dir() {
+        return None;
+    }
+    if !path.ends_with('/') {
+        return Some(Resolved::Directory);
+    }
+
+    let index = tokio::fs::canonicalize(candidate.join(INDEX_FILE))
+        .await
+        .ok()?;
+    let is_file = tokio::fs::metadata(&index).await.ok()?.is_file();
+    (is_file && index.starts_with(root)).then_some(Resolved::File(index))
+}
+
+async fn send_file(req: &Request<Body>, path: &Path, errors: &Errors<'_>) -> Response<Body> {
+    let not_found = format!("No file matches {}.", req.uri().path());
+    let mut file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(_) => return errors.respond(StatusCode::NOT_FOUND, &not_found).await,
+    };
+    let metadata = match file.metadata().await {
+        Ok(metadata) => metadata,
+        Err(_) => return errors.respond(StatusCode::NOT_FOUND, &not_found).await,
+    };
+
+    let len = metadata.len();
+    let modified = metadata.modified().ok();
+    let etag = entity_tag(len, modified);
+    let content_type = mime_guess::from_path(path).first_or_octet_stream();
+
+    let mut response = Response::builder()
+        .header(ETAG, &etag)
+        .header(ACCEPT_RANGES, "bytes");
+    if let Some(modified) = modified {
+        response = response.header(LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+
+    if not_modified(req, &etag, modified) {
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    let range = match requested_range(req, &etag, modified) {
+        Some(range) => match byte_range(range, len) {
+            Some(range) => Some(range),
+            None => {
+                return response
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(CONTENT_RANGE, format!("bytes */{}", len))
+                    .body(Body::empty())
+                    .unwrap();
+            }
+        },
+        None => None,
+    };
+
+    let (start, end) = range.unwrap_or((0, len.saturating_sub(1)));
+    let body_len = if len == 0 { 0 } else { end - start + 1 };
+    response = response
+        .header(CONTENT_TYPE, content_type.as_ref())
+        .header(CONTENT_LENGTH, body_len);
+    if range.is_some() {
+        response = response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len));
+    }
+
+    if req.method() == Method::HEAD || body_len == 0 {
+        return response.body(Body::empty()).unwrap();
+    }
+    if let Err(error) = file.seek(SeekFrom::Start(start)).await {
+        eprintln!("❌ Static file {}: {}", path.display(), error);
+        let reason = "The file could not be read.";
+        return errors
+            .respond(StatusCode::INTERNAL_SERVER_ERROR, reason)
+            .await;
+    }
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut remaining = body_len;
+        let mut buf = vec![0; CHUNK_SIZE];
+        while remaining > 0 {
+            let want = buf.len().min(remaining as usize);
+            let read = match file.read(&mut buf[..want]).await {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            remaining -= read as u64;
+            if sender
+                .send_data(hyper::body::Bytes::copy_from_slice(&buf[..read]))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    response.body(body).unwrap()
+}
+
+fn entity_tag(len: u64, modified: Option<SystemTime>) -> String {
+    let modified = modified
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos());
+    format!("\"{:x}-{:x}\"", len, modified)
+}
+
+/// Checks `If-None-Match`, and `If-Modified-Since` when the former is absent.
+fn not_modified(req: &Request<Body>, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = header_str(req, IF_NONE_MATCH) {
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+
+    match (header_str(req, IF_MODIFIED_SINCE), modified) {
+        (Some(since), Some(modified)) => match httpdate::parse_http_date(since) {
+            Ok(since) => unix_secs(modified) <= unix_secs(since),
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+/// HTTP dates have whole-second precision, so file times are compared at
+/// that precision too.
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// A single `bytes=` range, unless `If-Range` says the client's copy is stale.
+/// Multiple ranges are not supported and answered with the whole file.
+fn requested_range<'a>(
+    req: &'a Request<Body>,
+    etag: &str,
+    modified: Option<SystemTime>,
+) -> Option<&'a str> {
+    let range = header_str(req, RANGE)?.strip_prefix("bytes=")?;
+    if range.contains(',') {
+        return None;
+    }
+
+    if let Some(if_range) = header_str(req, IF_RANGE) {
+        let fresh = match httpdate::parse_http_date(if_range) {
+            Ok(date) => modified.is_some_and(|modified| unix_secs(modified) == unix_secs(date)),
+            Err(_) => if_range == etag,
+        };
+        if !fresh {
+            return None;
+        }
+    }
+
+    Some(range.trim())
+}
+
+/// Resolves `start-end`, `start-` or `-suffix` against a file of `len` bytes
+/// into an inclusive range, or `None` if it is unsatisfiable.
+fn byte_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (len.saturating_sub(suffix), len.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, len.checked_sub(1)?),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.min(len.checked_sub(1)?),
+        ),
+    };
+
+    (start <= end && start < len).then_some((start, end))
+}
+
+fn header_str(req: &Request<Body>, name: hyper::header::HeaderName) -> Option<&str> {
+    req.headers()
+        .get(name)
+        .and_then(|value: &HeaderValue| value.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A root with `index.html`, `docs/index.html` and a symlink to a file
+    /// outside of it, next to `secret.txt`.
+    fn site(name: &str) -> StaticRoute {
+        let dir = std::env::temp_dir().join(format!("prin-static-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("index.html"), "0123456789").unwrap();
+        std::fs::write(root.join("docs/index.html"), "docs").unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("leak.txt")).unwrap();
+        StaticRoute {
+            root: root.display().to_string(),
+            spa: false,
+        }
+    }
+
+    async fn get(route: &StaticRoute, path: &str, headers: &[(&str, &str)]) -> Response<Body> {
+        let mut req = Request::get(format!("/static{}", path));
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let req = req.body(Body::empty()).unwrap();
+        serve(route, &req, path, "/static", &HashMap::new()).await
+    }
+
+    async fn body(response: Response<Body>) -> String {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8_lossy(&body).into_owned()
+    }
+
+    #[tokio::test]
+    async fn traversal_stays_inside_the_root() {
+        let route = site("traversal");
+        for path in [
+            "/../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/..%2fsecret.txt",
+            "/leak.txt",
+        ] {
+            let response = get(&route, path, &[]).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+            assert_ne!(body(response).await, "secret", "{}", path);
+        }
+        assert_eq!(
+            get(&route, "/index.html", &[]).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn directory_without_slash_redirects() {
+        let route = site("directory");
+        let response = get(&route, "/docs", &[]).await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()[LOCATION], "/static/docs/");
+        assert_eq!(body(get(&route, "/docs/", &[]).await).await, "docs");
+    }
+
+    #[tokio::test]
+    async fn ranges_and_conditional_requests() {
+        let route = site("ranges");
+        let response = get(&route, "/index.html", &[("range", "bytes=2-4")]).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(body(response).await, "234");
+
+        let response = get(&route, "/index.html", &[("range", "bytes=20-")]).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */10");
+
+        let etag = get(&route, "/index.html", &[]).await.headers()[ETAG].clone();
+        let etag = etag.to_str().unwrap();
+        let response = get(&route, "/index.html", &[("if-none-match", etag)]).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(body(response).await, "");
+    }
+}
@@ -0,0 +1,73 @@
+use hyper::header::HeaderValue;
+use hyper::{Body, Request};
+
+use crate::config::{SameSite, StickySession};
+use crate::predicate;
+
+/// The cookie of a route with `sticky` sessions.
+pub struct Affinity {
+    cookie: String,
+    /// Everything after `name=value` in the `Set-Cookie` header.
+    attributes: String,
+}
+
+impl Affinity {
+    pub fn new(sticky: &StickySession) -> Result<Self, String> {
+        let invalid = |c: char| {
+            c.is_ascii_control() || c.is_whitespace() || "()<>@,;:\\\"/[]?={}".contains(c)
+        };
+        if sticky.cookie.is_empty() || sticky.cookie.contains(invalid) || !sticky.cookie.is_ascii()
+        {
+            return Err(format!("invalid sticky cookie name {:?}", sticky.cookie));
+        }
+        if sticky.same_site == Some(SameSite::None) && !sticky.secure {
+            return Err("sticky cookies with same_site none must be secure".to_string());
+        }
+
+        let mut attributes = format!("; Path={}", sticky.path);
+        if let Some(domain) = &sticky.domain {
+            attributes.push_str(&format!("; Domain={}", domain));
+        }
+        if let Some(ttl) = sticky.ttl_ms {
+            // Max-Age counts seconds; rounding down could make it 0, which
+            // deletes the cookie.
+            attributes.push_str(&format!("; Max-Age={}", ttl.div_ceil(1000)));
+        }
+        if sticky.secure {
+            attributes.push_str("; Secure");
+        }
+        if sticky.http_only {
+            attributes.push_str("; HttpOnly");
+        }
+        if let Some(same_site) = sticky.same_site {
+            attributes.push_str(&format!("; SameSite={}", same_site));
+        }
+        if attributes.contains(|c: char| c.is_ascii_control() || !c.is_ascii())
+            || sticky.path.contains(';')
+            || sticky
+                .domain
+                .as_deref()
+                .is_some_and(|domain| domain.contains(';'))
+        {
+            return Err("invalid sticky cookie path or domain".to_string());
+        }
+
+        Ok(Affinity {
+            cookie: sticky.cookie.clone(),
+            attributes,
+        })
+    }
+
+    /// The upstream id the request's cookie pins it to, if it has one.
+    pub fn pinned<'a>(&self, req: &'a Request<Body>) -> Option<&'a str> {
+        predicate::cookies(req)
+            .find(|(name, _)| *name == self.cookie)
+            .map(|(_, value)| value)
+    }
+
+    /// The `Set-Cookie` header that pins a client to the upstream with `id`.
+    pub fn set_cookie(&self, id: &str) -> HeaderValue {
+        let cookie = format!("{}={}{}", self.cookie, id, self.attributes);
+        HeaderValue::from_str(&cookie).expect("checked when the route was loaded")
+    }
+}
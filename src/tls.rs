@@ -0,0 +1,242 @@
+use colored::*;
+use rustls::client::{ResolvesClientCert, Resumption, ServerCertVerified, ServerCertVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName, SignatureScheme};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::{UpstreamProtocol, UpstreamTls};
+
+/// The client side of TLS toward upstreams for one proxy route.
+#[derive(Clone)]
+pub struct TlsSettings {
+    pub config: Arc<ClientConfig>,
+    /// Overrides the target's host as SNI and as the name to verify.
+    pub server_name: Option<ServerName>,
+}
+
+impl TlsSettings {
+    /// Builds the settings for a route, trusting the system's certificate
+    /// authorities unless `tls` names a CA file. A client certificate is
+    /// loaded right away, so broken files fail at startup. ALPN offers the
+    /// HTTP versions `protocol` allows.
+    pub fn new(tls: Option<&UpstreamTls>, protocol: UpstreamProtocol) -> Result<Self, String> {
+        let default = UpstreamTls::default();
+        let tls = tls.unwrap_or(&default);
+
+        let roots = match &tls.ca_file {
+            Some(ca_file) => load_ca_file(ca_file)?,
+            None => system_roots().clone(),
+        };
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let mut config = match (&tls.client_cert, &tls.client_key) {
+            (Some(cert_file), Some(key_file)) => builder.with_client_cert_resolver(Arc::new(
+                ClientIdentity::load(cert_file.clone(), key_file.clone())?,
+            )),
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err("tls: client_cert and client_key go together".to_string()),
+        };
+        config.alpn_protocols = match protocol {
+            UpstreamProtocol::Http1 => vec![b"http/1.1".to_vec()],
+            UpstreamProtocol::Auto => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            UpstreamProtocol::Http2 => vec![b"h2".to_vec()],
+        };
+        if tls.client_cert.is_some() {
+            // A resumed session keeps the identity it was started with, so
+            // a reloaded certificate would only be used once it expires.
+            config.resumption = Resumption::disabled();
+        }
+        if tls.insecure_skip_verify {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(SkipVerification));
+        }
+
+        let server_name = tls
+            .server_name
+            .as_deref()
+            .map(|name| {
+                ServerName::try_from(name).map_err(|_| format!("tls: invalid server_name {}", name))
+            })
+            .transpose()?;
+
+        Ok(TlsSettings {
+            config: Arc::new(config),
+            server_name,
+        })
+    }
+}
+
+/// The system's certificate authorities, loaded once for all routes.
+fn system_roots() -> &'static RootCertStore {
+    static ROOTS: OnceLock<RootCertStore> = OnceLock::new();
+    ROOTS.get_or_init(|| {
+        let mut roots = RootCertStore::empty();
+        match rustls_native_certs::load_native_certs() {
+            Ok(certs) => {
+                for cert in certs {
+                    // Certificates rustls can't parse are skipped, as any
+                    // TLS client would.
+                    let _ = roots.add(&Certificate(cert.0));
+                }
+            }
+            Err(error) => eprintln!(
+                "{}",
+                format!("❌ Could not load system certificates: {}", error).red()
+            ),
+        }
+        roots
+    })
+}
+
+fn load_ca_file(path: &str) -> Result<RootCertStore, String> {
+    let pem = std::fs::read(path).map_err(|e| format!("tls: ca_file {}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .map_err(|e| format!("tls: ca_file {}: {}", path, e))?;
+
+    let mut roots = RootCertStore::empty();
+    for cert in certs {
+        roots
+            .add(&Certificate(cert))
+            .map_err(|e| format!("tls: ca_file {}: {}", path, e))?;
+    }
+    if roots.is_empty() {
+        return Err(format!("tls: ca_file {} holds no certificates", path));
+    }
+    Ok(roots)
+}
+
+/// How often at most the client certificate files are checked for changes.
+const RELOAD_CHECK: Duration = Duration::from_secs(1);
+
+/// The client certificate a route presents to its upstreams. Handshakes
+/// check whether the files changed, and pick up a renewed certificate
+/// without a restart. Connections already open keep the old one.
+struct ClientIdentity {
+    cert_file: String,
+    key_file: String,
+    current: Mutex<LoadedIdentity>,
+}
+
+struct LoadedIdentity {
+    key: Arc<CertifiedKey>,
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    checked: Instant,
+}
+
+impl ClientIdentity {
+    fn load(cert_file: String, key_file: String) -> Result<Self, String> {
+        let modified = (modified(&cert_file), modified(&key_file));
+        let key = load_identity(&cert_file, &key_file)?;
+        Ok(ClientIdentity {
+            cert_file,
+            key_file,
+            current: Mutex::new(LoadedIdentity {
+                key,
+                modified,
+                checked: Instant::now(),
+            }),
+        })
+    }
+
+    /// The current key, reloaded first if the files changed. If the new
+    /// files can't be loaded, e.g. while they are half written, the old
+    /// key stays in use and the next check tries again.
+    fn current(&self) -> Arc<CertifiedKey> {
+        let mut current = self.current.lock().unwrap();
+        if current.checked.elapsed() < RELOAD_CHECK {
+            return Arc::clone(&current.key);
+        }
+        current.checked = Instant::now();
+
+        let modified = (modified(&self.cert_file), modified(&self.key_file));
+        if modified != current.modified {
+            match load_identity(&self.cert_file, &self.key_file) {
+                Ok(key) => {
+                    current.key = key;
+                    current.modified = modified;
+                    println!(
+                        "{}",
+                        format!("🔄 Reloaded client certificate {}", self.cert_file).green()
+                    );
+                }
+                Err(error) => eprintln!(
+                    "{}",
+                    format!("❌ Could not reload client certificate: {}", error).red()
+                ),
+            }
+        }
+        Arc::clone(&current.key)
+    }
+}
+
+impl ResolvesClientCert for ClientIdentity {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load_identity(cert_file: &str, key_file: &str) -> Result<Arc<CertifiedKey>, String> {
+    let pem =
+        std::fs::read(cert_file).map_err(|e| format!("tls: client_cert {}: {}", cert_file, e))?;
+    let chain: Vec<Certificate> = rustls_pemfile::certs(&mut pem.as_slice())
+        .map_err(|e| format!("tls: client_cert {}: {}", cert_file, e))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if chain.is_empty() {
+        return Err(format!(
+            "tls: client_cert {} holds no certificates",
+            cert_file
+        ));
+    }
+
+    let pem =
+        std::fs::read(key_file).map_err(|e| format!("tls: client_key {}: {}", key_file, e))?;
+    let key = rustls_pemfile::read_all(&mut pem.as_slice())
+        .map_err(|e| format!("tls: client_key {}: {}", key_file, e))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("tls: client_key {} holds no private key", key_file))?;
+    let key = rustls::sign::any_supported_type(&key)
+        .map_err(|_| format!("tls: client_key {}: unsupported key type", key_file))?;
+
+    Ok(Arc::new(CertifiedKey::new(chain, key)))
+}
+
+/// Accepts whatever certificate the upstream presents, for
+/// `insecure_skip_verify`.
+struct SkipVerification;
+
+impl ServerCertVerifier for SkipVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
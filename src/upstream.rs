@@ -0,0 +1,765 @@
+use colored::*;
+use hyper::body::HttpBody;
+use hyper::client::connect::{capture_connection, HttpInfo};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, CONNECTION, PROXY_AUTHORIZATION, TE, UPGRADE,
+};
+use hyper::http::uri::Scheme;
+use hyper::service::Service;
+use hyper::upgrade::OnUpgrade;
+use hyper::{Body, Client, Request, Response, StatusCode, Uri, Version};
+use std::collections::HashMap;
+use std::error::Error as _;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::balancer::Outstanding;
+use crate::config::{
+    ConnectionPool, ProxyConfig, ProxyRoute, Timeouts, UpstreamProtocol, UpstreamProxy, UpstreamTls,
+};
+use crate::connector::Connector;
+use crate::dns::Dns;
+use crate::pool::{InFlight, PoolStats, Pools};
+use crate::tls::TlsSettings;
+use crate::upstream_proxy::ForwardProxy;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Headers that describe one connection rather than the message, and must
+/// not be passed on by a proxy.
+const HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailers",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Why a request did not get a response from its upstream.
+#[derive(Debug)]
+pub enum UpstreamError {
+    /// No connection could be made: refused, unreachable, or the host name
+    /// did not resolve.
+    Connect(hyper::Error),
+    /// The connection broke before a complete response arrived.
+    Reset(hyper::Error),
+    /// A timeout ran out before the response headers arrived.
+    Timeout(Timeout),
+    /// Anything else that went wrong talking to the upstream.
+    Http(hyper::Error),
+    /// The request could not be turned into a forwarded request.
+    Request(String),
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Connect(error) => write!(f, "could not connect: {}", cause(error)),
+            UpstreamError::Reset(error) => write!(f, "connection broke: {}", cause(error)),
+            UpstreamError::Timeout(timeout) => write!(f, "{}", timeout),
+            UpstreamError::Http(error) => write!(f, "{}", cause(error)),
+            UpstreamError::Request(reason) => write!(f, "invalid forwarded request: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+/// Which timeout ran out, and after how long.
+#[derive(Debug)]
+pub struct Timeout {
+    pub kind: &'static str,
+    pub after: Duration,
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} timeout after {}ms",
+            self.kind,
+            self.after.as_millis()
+        )
+    }
+}
+
+/// Sends forwarded requests for one proxy route, with the route's timeouts.
+pub struct UpstreamClient {
+    client: Client<Connector>,
+    connector: Connector,
+    timeouts: Timeouts,
+    dns: Arc<Dns>,
+}
+
+/// What makes two routes connect differently. Routes that agree on all of
+/// it share a client, and with it their idle connections.
+#[derive(PartialEq, Eq, Hash)]
+struct TransportKey {
+    tls: Option<UpstreamTls>,
+    protocol: UpstreamProtocol,
+    upstream_proxy: Option<UpstreamProxy>,
+    connect_ms: Option<u64>,
+}
+
+/// The connections of all routes: one pool per upstream host, shared by
+/// every route so that `max_connections_per_host` holds across them, and a
+/// client for each distinct way of connecting.
+pub struct Transports {
+    pools: Arc<Pools>,
+    dns: Arc<Dns>,
+    clients: Mutex<HashMap<TransportKey, (Client<Connector>, Connector)>>,
+}
+
+impl Transports {
+    pub fn new(pool: &ConnectionPool, dns: &Arc<Dns>) -> Self {
+        Transports {
+            pools: Arc::new(Pools::new(pool.max_connections_per_host)),
+            dns: Arc::clone(dns),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Connection counts for each upstream host connected to so far.
+    pub fn pool_stats(&self) -> Vec<PoolStats> {
+        self.pools.stats()
+    }
+
+    /// The client for `key`, built with the pool settings from `config` the
+    /// first time it is asked for.
+    fn client(
+        &self,
+        key: TransportKey,
+        config: &ProxyConfig,
+    ) -> Result<(Client<Connector>, Connector), String> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(shared) = clients.get(&key) {
+            return Ok(shared.clone());
+        }
+
+        let upstream_proxy = key
+            .upstream_proxy
+            .as_ref()
+            .map(ForwardProxy::from_env)
+            .transpose()?;
+        let connector = Connector::new(
+            key.connect_ms.map(Duration::from_millis),
+            TlsSettings::new(key.tls.as_ref(), key.protocol)?,
+            &config.pool,
+            Arc::clone(&self.pools),
+            Arc::clone(&self.dns),
+            upstream_proxy,
+        );
+        let client = Client::builder()
+            .http2_only(key.protocol == UpstreamProtocol::Http2)
+            .pool_max_idle_per_host(config.pool.max_idle_per_host)
+            .pool_idle_timeout(Duration::from_millis(config.pool.idle_timeout_ms))
+            .build(connector.clone());
+        clients.insert(key, (client.clone(), connector.clone()));
+        Ok((client, connector))
+    }
+}
+
+impl UpstreamClient {
+    /// The client for `proxy`, with the global settings from `config` where
+    /// the route has none of its own. Fails if the TLS settings can't be
+    /// loaded or the forward proxy is misconfigured.
+    pub fn new(
+        proxy: &ProxyRoute,
+        config: &ProxyConfig,
+        transports: &Transports,
+    ) -> Result<Self, String> {
+        let timeouts = proxy.timeouts.resolve(&config.timeouts);
+        let key = TransportKey {
+            tls: proxy.tls.clone(),
+            protocol: proxy.protocol,
+            upstream_proxy: proxy
+                .upstream_proxy
+                .clone()
+                .or_else(|| config.upstream_proxy.clone()),
+            connect_ms: timeouts.connect_ms,
+        };
+        let (client, connector) = transports.client(key, config)?;
+        Ok(UpstreamClient {
+            client,
+            connector,
+            timeouts,
+            dns: Arc::clone(&transports.dns),
+        })
+    }
+
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
+    /// The forward proxy this client connects through, if any.
+    pub fn upstream_proxy(&self) -> Option<&ForwardProxy> {
+        self.connector.proxy()
+    }
+
+    /// Sends a plain `GET` to `uri`, for health checks.
+    pub async fn get(&self, uri: Uri) -> Result<Response<Body>, hyper::Error> {
+        let mut req = Request::get(uri)
+            .body(Body::empty())
+            .expect("a GET with a valid URI is a valid request");
+        self.authorize_proxy(&mut req);
+        self.client.request(req).await
+    }
+
+    /// Adds the forward proxy's credentials to a plain HTTP request going
+    /// through it. Tunnels for `https://` carry them in their `CONNECT`.
+    fn authorize_proxy(&self, req: &mut Request<Body>) {
+        if req.uri().scheme() != Some(&Scheme::HTTP) {
+            return;
+        }
+        let proxy = self.connector.proxy_for(req.uri());
+        if let Some(authorization) = proxy.and_then(|proxy| proxy.authorization()) {
+            req.headers_mut()
+                .insert(PROXY_AUTHORIZATION, authorization.clone());
+        }
+    }
+
+    /// Opens a connection to the upstream at `uri` and closes it again.
+    pub async fn connect(&self, uri: Uri) -> Result<(), String> {
+        let mut connector = self.connector.clone();
+        connector
+            .call(uri)
+            .await
+            .map(drop)
+            .map_err(|e| e.to_string())
+    }
+
+    /// When a request starting now has to be done, retries included.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.timeouts
+            .total_ms
+            .map(|total| Instant::now() + Duration::from_millis(total))
+    }
+
+    /// Sends `req`, whose URI already points at the upstream, on behalf of
+    /// `client_ip`. Hop-by-hop headers are dropped both ways, except for
+    /// `TE: trailers` which gRPC relies on and the `Upgrade` of a protocol
+    /// switch, and the client is appended to `X-Forwarded-For`; the `Host`
+    /// header is passed on as the client sent it.
+    ///
+    /// When the upstream agrees to switch protocols, e.g. to a WebSocket,
+    /// its `101` is passed on and both connections are joined.
+    /// `outstanding` is held until the response body or the joined
+    /// connection is done.
+    pub async fn send(
+        &self,
+        client_ip: IpAddr,
+        mut req: Request<Body>,
+        deadline: Option<Instant>,
+        outstanding: Outstanding,
+    ) -> Result<Response<Body>, UpstreamError> {
+        let trailers = accepts_trailers(req.headers());
+        let upgrade = upgrade_protocol(req.headers());
+        remove_hop_headers(req.headers_mut());
+        if trailers {
+            req.headers_mut()
+                .insert(TE, HeaderValue::from_static("trailers"));
+        }
+        let client_upgrade = upgrade.map(|protocol| {
+            set_upgrade(req.headers_mut(), protocol);
+            hyper::upgrade::on(&mut req)
+        });
+        add_forwarded_for(req.headers_mut(), client_ip)?;
+        self.authorize_proxy(&mut req);
+        // The version is set per connection: a request that came in over
+        // HTTP/2 may still go out over HTTP/1.1, and the other way round.
+        if req.version() == Version::HTTP_2 {
+            *req.version_mut() = Version::HTTP_11;
+        }
+
+        let in_flight = self.connector.pools().host(req.uri()).start_request();
+        let host = req.uri().host().unwrap_or_default().to_string();
+        let proxied = self.connector.proxy_for(req.uri()).is_some();
+        let mut connection = capture_connection(&mut req);
+
+        // The first-byte timer starts once there is a connection; until then
+        // the connect timeout and the deadline apply.
+        let request = self.client.request(req);
+        tokio::pin!(request);
+        let connecting = async {
+            tokio::select! {
+                response = &mut request => Some(response),
+                _ = connection.wait_for_connection_metadata() => None,
+            }
+        };
+        let response = match within(limit(None, deadline, "total"), connecting).await? {
+            Some(response) => response,
+            None => {
+                let first_byte = self.timeouts.first_byte_ms.map(Duration::from_millis);
+                within(limit(first_byte, deadline, "first byte"), request).await?
+            }
+        };
+        let mut response = response.map_err(|error| classify(error, &self.timeouts))?;
+
+        // Once the host's name resolves elsewhere, connections to the old
+        // address are not reused. Through a forward proxy, the proxy
+        // resolves it.
+        let info = response.extensions().get::<HttpInfo>().filter(|_| !proxied);
+        if let Some(info) = info {
+            if !self.dns.is_current(&host, info.remote_addr().ip()) {
+                if let Some(connected) = connection.connection_metadata().as_ref() {
+                    connected.poison();
+                }
+            }
+        }
+
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            if let Some(client_upgrade) = client_upgrade {
+                return Ok(tunnel(response, client_upgrade, in_flight, outstanding));
+            }
+        }
+
+        remove_hop_headers(response.headers_mut());
+        let idle = self.timeouts.idle_ms.map(Duration::from_millis);
+        Ok(response.map(|body| watch_body(body, idle, deadline, in_flight, outstanding)))
+    }
+}
+
+/// The tighter of `timeout` and the time left until `deadline`, and which
+/// of the two it is.
+fn limit(
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    kind: &'static str,
+) -> (Option<Duration>, &'static str) {
+    let left = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+    match (timeout, left) {
+        (Some(timeout), Some(left)) if left < timeout => (Some(left), "total"),
+        (Some(timeout), _) => (Some(timeout), kind),
+        (None, left) => (left, "total"),
+    }
+}
+
+/// Awaits `future`, giving up with a timeout of `kind` after `limit`.
+async fn within<T>(
+    (limit, kind): (Option<Duration>, &'static str),
+    future: impl Future<Output = T>,
+) -> Result<T, UpstreamError> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, future)
+            .await
+            .map_err(|_| UpstreamError::Timeout(Timeout { kind, after: limit })),
+        None => Ok(future.await),
+    }
+}
+
+/// Streams `body` on to the client, cutting it off if the upstream sends
+/// nothing for `idle` or the `deadline` passes. Headers are already on their
+/// way by then, so the client sees a truncated response. The request counts
+/// as in flight, and as outstanding on its upstream, until the body is done.
+fn watch_body(
+    mut body: Body,
+    idle: Option<Duration>,
+    deadline: Option<Instant>,
+    in_flight: InFlight,
+    outstanding: Outstanding,
+) -> Body {
+    let (mut sender, watched) = Body::channel();
+    tokio::spawn(async move {
+        let _in_flight = (in_flight, outstanding);
+        loop {
+            let (limit, kind) = limit(idle, deadline, "idle");
+            let chunk = match limit {
+                Some(limit) => match tokio::time::timeout(limit, body.data()).await {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        let timeout = Timeout { kind, after: limit };
+                        eprintln!(
+                            "{}",
+                            format!("❌ Upstream response cut off: {}", timeout).red()
+                        );
+                        sender.abort();
+                        return;
+                    }
+                },
+                None => body.data().await,
+            };
+            match chunk {
+                Some(Ok(chunk)) => {
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Err(_)) => {
+                    sender.abort();
+                    return;
+                }
+                None => break,
+            }
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    watched
+}
+
+/// Passes on the upstream's `101 Switching Protocols` and, once the client
+/// has switched too, relays bytes both ways until either side closes. The
+/// timeouts are over by then, like for any response whose headers arrived.
+fn tunnel(
+    mut response: Response<Body>,
+    client: OnUpgrade,
+    in_flight: InFlight,
+    outstanding: Outstanding,
+) -> Response<Body> {
+    let protocol = response.headers().get(UPGRADE).cloned();
+    let upstream = hyper::upgrade::on(&mut response);
+    remove_hop_headers(response.headers_mut());
+    if let Some(protocol) = protocol {
+        set_upgrade(response.headers_mut(), protocol);
+    }
+
+    tokio::spawn(async move {
+        let _in_flight = (in_flight, outstanding);
+        match tokio::try_join!(client, upstream) {
+            Ok((mut client, mut upstream)) => {
+                // Either side going away ends the tunnel; that is not worth
+                // reporting.
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+            }
+            Err(error) => eprintln!(
+                "{}",
+                format!("❌ Upgraded connection failed: {}", cause(&error)).red()
+            ),
+        }
+    });
+    response
+}
+
+/// The protocol `headers` ask to switch to, if they carry both `Upgrade`
+/// and `Connection: upgrade`.
+pub fn upgrade_protocol(headers: &HeaderMap) -> Option<HeaderValue> {
+    let requested = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case("upgrade"));
+    headers.get(UPGRADE).filter(|_| requested).cloned()
+}
+
+fn set_upgrade(headers: &mut HeaderMap, protocol: HeaderValue) {
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(UPGRADE, protocol);
+}
+
+/// Drops hop-by-hop headers, including any the `Connection` header names.
+fn remove_hop_headers(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+/// Whether the client said it accepts trailers in the response.
+fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case("trailers"))
+}
+
+fn add_forwarded_for(headers: &mut HeaderMap, client_ip: IpAddr) -> Result<(), UpstreamError> {
+    let value = match headers.get(X_FORWARDED_FOR) {
+        Some(existing) => {
+            let existing = existing
+                .to_str()
+                .map_err(|e| UpstreamError::Request(format!("{}: {}", X_FORWARDED_FOR, e)))?;
+            format!("{}, {}", existing, client_ip)
+        }
+        None => client_ip.to_string(),
+    };
+    let value = HeaderValue::from_str(&value)
+        .map_err(|e| UpstreamError::Request(format!("{}: {}", X_FORWARDED_FOR, e)))?;
+    headers.insert(X_FORWARDED_FOR, value);
+    Ok(())
+}
+
+fn classify(error: hyper::Error, timeouts: &Timeouts) -> UpstreamError {
+    if error.is_connect() {
+        if io_error_kind(&error) == Some(io::ErrorKind::TimedOut) {
+            if let Some(connect) = timeouts.connect_ms {
+                return UpstreamError::Timeout(Timeout {
+                    kind: "connect",
+                    after: Duration::from_millis(connect),
+                });
+            }
+        }
+        return UpstreamError::Connect(error);
+    }
+
+    let reset = matches!(
+        io_error_kind(&error),
+        Some(
+            io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+        )
+    );
+    if reset || error.is_incomplete_message() || error.is_closed() {
+        return UpstreamError::Reset(error);
+    }
+    UpstreamError::Http(error)
+}
+
+/// Kind of the first I/O error behind `error`.
+fn io_error_kind(error: &hyper::Error) -> Option<io::ErrorKind> {
+    let mut source = error.source();
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<io::Error>() {
+            return Some(error.kind());
+        }
+        source = error.source();
+    }
+    None
+}
+
+/// The innermost cause of `error`, which is usually the informative part,
+/// e.g. "Connection refused (os error 111)".
+fn cause(error: &hyper::Error) -> String {
+    let mut cause: &dyn std::error::Error = error;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+    cause.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy;
+    use crate::router::Router;
+    use hyper::server::conn::AddrStream;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+    use std::convert::Infallible;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Reads up to the end of a message head, lowercased.
+    async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            if stream.read(&mut byte).await.unwrap() == 0 {
+                break;
+            }
+            head.push(byte[0]);
+        }
+        String::from_utf8_lossy(&head).to_ascii_lowercase()
+    }
+
+    /// An upstream that switches requests asking for it to an `echo`
+    /// protocol, which sends back whatever it receives.
+    async fn echo_upstream() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let head = read_head(&mut stream).await;
+                    if !head.contains("\r\nupgrade: echo\r\n")
+                        || !head.contains("\r\nconnection: upgrade\r\n")
+                    {
+                        let _ = stream
+                            .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                            .await;
+                        return;
+                    }
+                    let switched =
+                        b"HTTP/1.1 101 Switching Protocols\r\nConnection: upgrade\r\nUpgrade: echo\r\n\r\n";
+                    let _ = stream.write_all(switched).await;
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        port
+    }
+
+    /// prin with a single catch-all route to `target`, on a free port.
+    fn start(target: &str) -> u16 {
+        let config: ProxyConfig = serde_json::from_value(serde_json::json!({
+            "routes": { "/": { "target": target } },
+        }))
+        .unwrap();
+        let router = Arc::new(Router::new(&config).unwrap());
+        let make_svc = make_service_fn(move |conn: &AddrStream| {
+            let remote_addr = conn.remote_addr().ip();
+            let router = Arc::clone(&router);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    proxy::handle_request(remote_addr, req, Arc::clone(&router))
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let port = server.local_addr().port();
+        tokio::spawn(server);
+        port
+    }
+
+    #[tokio::test]
+    async fn upgrades_are_relayed_both_ways() {
+        let upstream = echo_upstream().await;
+        let port = start(&format!("http://127.0.0.1:{}", upstream));
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream
+            .write_all(b"GET /ws HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("http/1.1 101"), "{}", head);
+        assert!(head.contains("\r\nupgrade: echo\r\n"), "{}", head);
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+
+    #[tokio::test]
+    async fn streaming_response_stays_outstanding() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_head(&mut stream).await;
+            let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\n";
+            stream.write_all(head.as_bytes()).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            stream.write_all(b"1\r\nb\r\n0\r\n\r\n").await.unwrap();
+            let _ = stream.read(&mut [0]).await;
+        });
+
+        let config: ProxyConfig = serde_json::from_value(serde_json::json!({
+            "routes": { "/": {
+                "targets": [format!("http://127.0.0.1:{}", upstream)],
+                "balance": "least_outstanding",
+            } },
+        }))
+        .unwrap();
+        let router = Arc::new(Router::new(&config).unwrap());
+        let (balancer, _) = router.proxies().next().unwrap();
+        let upstream = Arc::clone(&balancer.upstreams()[0]);
+
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        let client_ip = IpAddr::from([127, 0, 0, 1]);
+        let response = proxy::handle_request(client_ip, req, Arc::clone(&router))
+            .await
+            .unwrap();
+        assert_eq!(upstream.outstanding(), 1);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"ab");
+        assert_eq!(upstream.outstanding(), 0);
+    }
+
+    #[tokio::test]
+    async fn routes_to_the_same_host_share_connections() {
+        let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let accepted = Arc::clone(&connections);
+        let make_svc = make_service_fn(move |_: &AddrStream| {
+            accepted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            async {
+                Ok::<_, Infallible>(service_fn(|_| async {
+                    Ok::<_, Infallible>(Response::new(Body::from("ok")))
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let upstream = format!("http://127.0.0.1:{}", server.local_addr().port());
+        tokio::spawn(server);
+
+        let config: ProxyConfig = serde_json::from_value(serde_json::json!({
+            "routes": {
+                "/a": { "target": upstream.clone() },
+                "/b": { "target": upstream.clone(), "timeouts": { "first_byte_ms": 5000 } },
+            },
+            "pool": { "max_connections_per_host": 1 },
+        }))
+        .unwrap();
+        let router = Arc::new(Router::new(&config).unwrap());
+        for path in ["/a", "/b", "/a"] {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            let client_ip = IpAddr::from([127, 0, 0, 1]);
+            let response = proxy::handle_request(client_ip, req, Arc::clone(&router))
+                .await
+                .unwrap();
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+        }
+
+        assert_eq!(connections.load(std::sync::atomic::Ordering::Relaxed), 1);
+        let stats = router.pool_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!((stats[0].open, stats[0].in_flight), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn plain_requests_do_not_ask_to_upgrade() {
+        let upstream = echo_upstream().await;
+        let port = start(&format!("http://127.0.0.1:{}", upstream));
+
+        // `Upgrade` alone, without `Connection: upgrade`, is not a request
+        // to switch and is dropped like any hop-by-hop header.
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: echo\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("http/1.1 400"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn first_byte_timer_starts_once_connected() {
+        let make_svc = make_service_fn(|_: &AddrStream| async {
+            Ok::<_, Infallible>(service_fn(|_| async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                Ok::<_, Infallible>(Response::new(Body::from("ok")))
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let upstream = format!("http://127.0.0.1:{}", server.local_addr().port());
+        tokio::spawn(server);
+
+        // With one connection allowed, the second request waits ~200ms for
+        // it before its own 200ms response.
+        let config: ProxyConfig = serde_json::from_value(serde_json::json!({
+            "routes": { "/": { "target": upstream, "timeouts": { "first_byte_ms": 300 } } },
+            "pool": { "max_connections_per_host": 1 },
+        }))
+        .unwrap();
+        let router = Arc::new(Router::new(&config).unwrap());
+        let send = || {
+            let req = Request::get("/").body(Body::empty()).unwrap();
+            proxy::handle_request(IpAddr::from([127, 0, 0, 1]), req, Arc::clone(&router))
+        };
+        let (first, second) = tokio::join!(send(), send());
+        assert_eq!(first.unwrap().status(), StatusCode::OK);
+        assert_eq!(second.unwrap().status(), StatusCode::OK);
+    }
+}
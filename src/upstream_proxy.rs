@@ -0,0 +1,460 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hyper::header::HeaderValue;
+use hyper::http::uri::Scheme;
+use hyper::Uri;
+use std::env;
+use std::io;
+use std::net::IpAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config::UpstreamProxy;
+
+/// Longest `CONNECT` response header accepted from the proxy.
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+/// A route's forward proxy, ready to connect through.
+pub struct ForwardProxy {
+    /// Where the proxy listens.
+    uri: Uri,
+    /// `Proxy-Authorization` for the proxy, with basic auth.
+    authorization: Option<HeaderValue>,
+    /// `NO_PROXY` entries, lowercased.
+    no_proxy: Vec<String>,
+}
+
+impl ForwardProxy {
+    /// The proxy for `config`, bypassed for its `no_proxy` entries and
+    /// those in the `NO_PROXY` environment variable.
+    pub fn from_env(config: &UpstreamProxy) -> Result<Self, String> {
+        let env_no_proxy = ["NO_PROXY", "no_proxy"]
+            .iter()
+            .find_map(|name| env::var(name).ok())
+            .unwrap_or_default();
+        ForwardProxy::new(config, &env_no_proxy)
+    }
+
+    /// The proxy for `config`, with `env_no_proxy` read like `NO_PROXY`.
+    pub fn new(config: &UpstreamProxy, env_no_proxy: &str) -> Result<Self, String> {
+        let uri: Uri = config
+            .url
+            .parse()
+            .map_err(|_| format!("invalid upstream_proxy url {}", config.url))?;
+        if uri.scheme() != Some(&Scheme::HTTP) || uri.host().is_none() {
+            return Err(format!(
+                "upstream_proxy url {} must look like http://host:port",
+                config.url
+            ));
+        }
+        if uri.authority().is_some_and(|a| a.as_str().contains('@')) {
+            return Err("upstream_proxy credentials go in username and password".to_string());
+        }
+
+        let authorization = match (&config.username, &config.password) {
+            (Some(username), password) => {
+                let credentials = format!("{}:{}", username, password.as_deref().unwrap_or(""));
+                let value = format!("Basic {}", BASE64.encode(credentials));
+                Some(HeaderValue::from_str(&value).expect("base64 is a valid header value"))
+            }
+            (None, Some(_)) => return Err("upstream_proxy password needs a username".to_string()),
+            (None, None) => None,
+        };
+
+        let no_proxy = config
+            .no_proxy
+            .iter()
+            .map(String::as_str)
+            .chain(env_no_proxy.split(','))
+            .map(|entry| entry.trim().to_ascii_lowercase())
+            .filter(|entry| !entry.is_empty())
+            .collect();
+
+        Ok(ForwardProxy {
+            uri,
+            authorization,
+            no_proxy,
+        })
+    }
+
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    pub fn authorization(&self) -> Option<&HeaderValue> {
+        self.authorization.as_ref()
+    }
+
+    pub fn summary(&self) -> String {
+        let mut summary = format!("🛰️ via proxy {}", self.uri.authority().unwrap());
+        if self.authorization.is_some() {
+            summary.push_str(" with basic auth");
+        }
+        if !self.no_proxy.is_empty() {
+            summary.push_str(&format!(", direct to {}", self.no_proxy.join(", ")));
+        }
+        summary
+    }
+
+    /// Whether connections to `uri` go through the proxy, i.e. its host is
+    /// not covered by `NO_PROXY`.
+    pub fn applies_to(&self, uri: &Uri) -> bool {
+        let host = uri.host().unwrap_or_default();
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let host = host.to_ascii_lowercase();
+        let port = port(uri);
+        !self
+            .no_proxy
+            .iter()
+            .any(|entry| bypasses(entry, &host, port))
+    }
+
+    /// Asks the proxy, already connected on `stream`, to open a tunnel to
+    /// the host of `uri`.
+    pub async fn tunnel(&self, stream: &mut TcpStream, uri: &Uri) -> io::Result<()> {
+        let host = uri.host().unwrap_or_default();
+        let target = format!("{}:{}", host, port(uri));
+        let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+        if let Some(authorization) = &self.authorization {
+            let authorization = authorization.to_str().unwrap_or_default();
+            request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read byte by byte so nothing after the header, which belongs to
+        // the tunnel, is consumed here.
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_RESPONSE_HEAD {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "proxy sent an oversized CONNECT response",
+                ));
+            }
+            let mut byte = [0];
+            if stream.read(&mut byte).await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "proxy closed the connection during CONNECT",
+                ));
+            }
+            head.push(byte[0]);
+        }
+
+        let head = String::from_utf8_lossy(&head);
+        let status_line = head.lines().next().unwrap_or_default();
+        let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+        match status {
+            "200" => Ok(()),
+            "407" => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "proxy requires authentication (407)",
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("proxy refused CONNECT {}: {}", target, status_line),
+            )),
+        }
+    }
+}
+
+/// The port `uri` points at, the scheme's default if it has none.
+fn port(uri: &Uri) -> u16 {
+    uri.port_u16().unwrap_or(match uri.scheme() {
+        Some(scheme) if *scheme == Scheme::HTTPS => 443,
+        _ => 80,
+    })
+}
+
+/// Whether the `NO_PROXY` entry covers `host` on `port`. Entries are `*`,
+/// a host, a domain that also covers its subdomains (`example.com`,
+/// `.example.com` or `*.example.com`), an IP address or a CIDR range, each
+/// optionally with a `:port`.
+fn bypasses(entry: &str, host: &str, port: u16) -> bool {
+    if entry == "*" {
+        return true;
+    }
+    let (pattern, entry_port) = split_port(entry);
+    if entry_port.is_some_and(|entry_port| entry_port != port) {
+        return false;
+    }
+    if let Some((network, bits)) = pattern.split_once('/') {
+        return match (network.parse(), bits.parse(), host.parse()) {
+            (Ok(network), Ok(bits), Ok(ip)) => in_network(ip, network, bits),
+            _ => false,
+        };
+    }
+    let domain = pattern.trim_start_matches("*.").trim_start_matches('.');
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.ends_with('.'))
+}
+
+/// Splits a trailing `:port` off a `NO_PROXY` entry. Bare IPv6 addresses
+/// have several colons and no port; with a port they are in brackets.
+fn split_port(entry: &str) -> (&str, Option<u16>) {
+    if let Some(rest) = entry.strip_prefix('[') {
+        return match rest.split_once(']') {
+            Some((ip, port)) => (ip, port.strip_prefix(':').and_then(|p| p.parse().ok())),
+            None => (entry, None),
+        };
+    }
+    match entry.split_once(':') {
+        Some((host, port)) if !port.contains(':') => (host, port.parse().ok()),
+        _ => (entry, None),
+    }
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, bits: u32) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) if bits <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) if bits <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProxyConfig;
+    use crate::proxy;
+    use crate::router::Router;
+    use hyper::{Body, Request, StatusCode};
+    use std::net::Ipv6Addr;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    /// `Proxy-Authorization` for `user:secret`.
+    const CREDENTIALS: &str = "Basic dXNlcjpzZWNyZXQ=";
+
+    /// What the stand-in proxy received: request line and credentials.
+    type Seen = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+    #[test]
+    fn bypasses_hosts_and_domains() {
+        assert!(bypasses("*", "anything.example", 80));
+        assert!(bypasses("example.com", "example.com", 80));
+        assert!(bypasses("example.com", "api.example.com", 80));
+        assert!(bypasses(".example.com", "example.com", 80));
+        assert!(bypasses("*.example.com", "deep.api.example.com", 443));
+        assert!(!bypasses("example.com", "badexample.com", 80));
+        assert!(!bypasses("api.example.com", "example.com", 80));
+    }
+
+    #[test]
+    fn bypasses_only_on_the_given_port() {
+        assert!(bypasses("localhost:8080", "localhost", 8080));
+        assert!(!bypasses("localhost:8080", "localhost", 80));
+        assert!(bypasses("[::1]:8080", "::1", 8080));
+        assert!(!bypasses("[::1]:8080", "::1", 443));
+        assert!(bypasses("10.0.0.0/8:443", "10.1.2.3", 443));
+        assert!(!bypasses("10.0.0.0/8:443", "10.1.2.3", 80));
+    }
+
+    #[test]
+    fn bypasses_ip_addresses_and_ranges() {
+        assert!(bypasses("127.0.0.1", "127.0.0.1", 80));
+        assert!(bypasses("::1", "::1", 80));
+        assert!(bypasses("[::1]", "::1", 80));
+        assert!(bypasses("10.0.0.0/8", "10.255.0.1", 80));
+        assert!(!bypasses("10.0.0.0/8", "11.0.0.1", 80));
+        assert!(bypasses("fd00::/8", "fd12::1", 80));
+        assert!(!bypasses("fd00::/8", "fe80::1", 80));
+        // Ranges only cover IP addresses, of the same family.
+        assert!(!bypasses("10.0.0.0/8", "internal.example", 80));
+        assert!(!bypasses("0.0.0.0/0", "::1", 80));
+    }
+
+    #[test]
+    fn split_port_handles_ipv6() {
+        assert_eq!(split_port("host:8080"), ("host", Some(8080)));
+        assert_eq!(split_port("host"), ("host", None));
+        assert_eq!(split_port("fd00::1"), ("fd00::1", None));
+        assert_eq!(split_port("fd00::/8"), ("fd00::/8", None));
+        assert_eq!(split_port("[fd00::1]:443"), ("fd00::1", Some(443)));
+        assert_eq!(split_port("[fd00::1]"), ("fd00::1", None));
+    }
+
+    #[test]
+    fn in_network_edges() {
+        let v4 = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(in_network(v4("192.168.1.7"), v4("192.168.1.0"), 24));
+        assert!(!in_network(v4("192.168.2.7"), v4("192.168.1.0"), 24));
+        assert!(in_network(v4("1.2.3.4"), v4("0.0.0.0"), 0));
+        assert!(in_network(v4("1.2.3.4"), v4("1.2.3.4"), 32));
+        assert!(!in_network(v4("1.2.3.5"), v4("1.2.3.4"), 32));
+        assert!(!in_network(v4("1.2.3.4"), v4("1.2.3.4"), 33));
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert!(in_network(v6, v6, 128));
+        assert!(in_network(v6, IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0));
+        assert!(in_network(v6, IpAddr::V6(Ipv6Addr::UNSPECIFIED), 127));
+        assert!(!in_network(v6, IpAddr::V6(Ipv6Addr::UNSPECIFIED), 128));
+        assert!(!in_network(v6, v6, 129));
+    }
+
+    /// A stand-in forward proxy. It records each request, answers 407 to
+    /// those without `CREDENTIALS`, echoes the request line of plain ones,
+    /// and accepts tunnels only to close them again.
+    async fn stand_in_proxy() -> (u16, Seen) {
+        let seen = Seen::default();
+        let recorded = Arc::clone(&seen);
+        let port = serve(move |head| {
+            let line = head.lines().next().unwrap_or_default().to_string();
+            let authorization = head
+                .lines()
+                .find_map(|line| line.strip_prefix("proxy-authorization: "))
+                .map(str::to_string);
+            let authorized = authorization.as_deref() == Some(CREDENTIALS);
+            recorded.lock().unwrap().push((line.clone(), authorization));
+            match (authorized, line.starts_with("CONNECT")) {
+                (false, _) => "HTTP/1.1 407 Proxy Authentication Required\r\n\r\n".to_string(),
+                (true, true) => "HTTP/1.1 200 Connection established\r\n\r\n".to_string(),
+                (true, false) => response(&line),
+            }
+        })
+        .await;
+        (port, seen)
+    }
+
+    /// Accepts connections on a free port and answers the first request
+    /// on each with `answer(head)`, its header names lowercased.
+    async fn serve(answer: impl Fn(&str) -> String + Send + Sync + 'static) -> u16 {
+        let answer = Arc::new(answer);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let answer = Arc::clone(&answer);
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut buffer = [0; 1024];
+                    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => head.extend_from_slice(&buffer[..n]),
+                        }
+                    }
+                    let head = String::from_utf8_lossy(&head);
+                    let (first, rest) = head.split_once("\r\n").unwrap_or_default();
+                    let fields = rest.lines().map(|line| match line.split_once(':') {
+                        Some((name, value)) => format!("{}:{}", name.to_ascii_lowercase(), value),
+                        None => line.to_string(),
+                    });
+                    let head = format!("{}\r\n{}", first, fields.collect::<Vec<_>>().join("\r\n"));
+                    let _ = stream.write_all(answer(&head).as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        port
+    }
+
+    fn response(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    /// Sends `GET /x` to a router with a single catch-all route to `target`.
+    async fn get(target: &str, upstream_proxy: serde_json::Value) -> (StatusCode, String) {
+        let config: ProxyConfig = serde_json::from_value(serde_json::json!({
+            "routes": { "/": { "target": target } },
+            "upstream_proxy": upstream_proxy,
+        }))
+        .unwrap();
+        let router = Arc::new(Router::new(&config).unwrap());
+        let req = Request::get("/x").body(Body::empty()).unwrap();
+        let client_ip = IpAddr::from([127, 0, 0, 1]);
+        let response = proxy::handle_request(client_ip, req, router).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    fn with_credentials(port: u16) -> serde_json::Value {
+        serde_json::json!({
+            "url": format!("http://127.0.0.1:{}", port),
+            "username": "user",
+            "password": "secret",
+        })
+    }
+
+    #[tokio::test]
+    async fn plain_http_goes_to_proxy_in_absolute_form() {
+        let (port, seen) = stand_in_proxy().await;
+        let (status, body) = get("http://upstream.test:8080/v1", with_credentials(port)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "GET http://upstream.test:8080/v1/x HTTP/1.1");
+        assert_eq!(
+            seen.lock().unwrap()[0].1.as_deref(),
+            Some(CREDENTIALS),
+            "plain requests carry Proxy-Authorization"
+        );
+    }
+
+    #[tokio::test]
+    async fn https_goes_through_connect_with_credentials() {
+        let (port, seen) = stand_in_proxy().await;
+        // The stand-in closes the tunnel, so the TLS handshake fails.
+        let (status, _) = get("https://upstream.test:8443", with_credentials(port)).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            seen.lock().unwrap()[0],
+            (
+                "CONNECT upstream.test:8443 HTTP/1.1".to_string(),
+                Some(CREDENTIALS.to_string())
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_tunnel_is_a_bad_gateway() {
+        let (port, _) = stand_in_proxy().await;
+        let upstream_proxy = serde_json::json!({ "url": format!("http://127.0.0.1:{}", port) });
+        let (status, body) = get("https://upstream.test:8443", upstream_proxy).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(
+            body.contains("proxy requires authentication (407)"),
+            "{}",
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn no_proxy_hosts_are_reached_directly() {
+        let (port, seen) = stand_in_proxy().await;
+        let direct = serve(|_| response("direct")).await;
+
+        let mut upstream_proxy = with_credentials(port);
+        upstream_proxy["no_proxy"] = serde_json::json!([format!("127.0.0.1:{}", direct)]);
+        let target = format!("http://127.0.0.1:{}", direct);
+        assert_eq!(get(&target, upstream_proxy).await.1, "direct");
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn env_no_proxy_adds_to_configured_entries() {
+        let config: UpstreamProxy = serde_json::from_value(serde_json::json!({
+            "url": "http://127.0.0.1:3128",
+            "no_proxy": ["internal.test"],
+        }))
+        .unwrap();
+        let proxy = ForwardProxy::new(&config, " Localhost, ,10.0.0.0/8").unwrap();
+        let applies = |uri: &str| proxy.applies_to(&uri.parse().unwrap());
+        assert!(!applies("http://internal.test/"));
+        assert!(!applies("http://localhost:8080/"));
+        assert!(!applies("https://10.1.2.3/"));
+        assert!(applies("http://example.test/"));
+        assert_eq!(proxy.no_proxy, ["internal.test", "localhost", "10.0.0.0/8"]);
+    }
+}